@@ -15,9 +15,62 @@
  *
  */
 use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 use url::Url;
 
+/// how the registrar is identified in the voucher-request.
+/// The pubk forms are for constrained (RFC9148) voucher-requests.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ProximityForm {
+    Cert,
+    Pubk,
+    PubkSha256,
+}
+
+impl FromStr for ProximityForm {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cert"        => Ok(ProximityForm::Cert),
+            "pubk"        => Ok(ProximityForm::Pubk),
+            "pubk-sha256" => Ok(ProximityForm::PubkSha256),
+            _ => Err(format!("unknown proximity form {}, use cert, pubk or pubk-sha256", s))
+        }
+    }
+}
+
+/// how the voucher-request is sent: COSE signed CBOR, or CMS or JWS signed JSON
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VoucherFormat {
+    Cose,
+    CmsJson,
+    JwsJson,
+}
+
+impl VoucherFormat {
+    pub const ALL: [VoucherFormat; 3] = [VoucherFormat::Cose, VoucherFormat::CmsJson, VoucherFormat::JwsJson];
+
+    /// this format first, then the others
+    pub fn preference(&self) -> Vec<VoucherFormat> {
+        let mut order = vec![*self];
+        order.extend(VoucherFormat::ALL.iter().filter(|f| *f != self));
+        order
+    }
+}
+
+impl FromStr for VoucherFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cose" => Ok(VoucherFormat::Cose),
+            "cms"  => Ok(VoucherFormat::CmsJson),
+            "jws"  => Ok(VoucherFormat::JwsJson),
+            _ => Err(format!("unknown voucher format {}, use cose, cms or jws", s))
+        }
+    }
+}
+
 #[derive(StructOpt, PartialEq, Debug)]
 /// Hermes Bootstrap manager
 pub struct BootstrapOptions {
@@ -29,7 +82,8 @@ pub struct BootstrapOptions {
     #[structopt(long, parse(try_from_str = Url::parse))]
     pub registrar: Option<Url>,
 
-    /// where to find the IDevID certificate
+    /// where to find the IDevID certificate, and any intermediate
+    /// certificates after it, which are sent in the TLS handshake
     #[structopt(long, parse(from_os_str))]
     pub idevid_cert: Option<PathBuf>,
 
@@ -37,9 +91,64 @@ pub struct BootstrapOptions {
     #[structopt(long, parse(from_os_str))]
     pub idevid_priv: Option<PathBuf>,
 
+    /// MASA trust anchor, used to validate vouchers
+    #[structopt(long, parse(from_os_str))]
+    pub masa_cert: Option<PathBuf>,
+
     /// output file for LDevID after enrollment
     #[structopt(long, parse(from_os_str))]
     pub ldevid_cert: Option<PathBuf>,
+
+    /// output file for the LDevID private key, written mode 0600
+    #[structopt(long, parse(from_os_str))]
+    pub ldevid_priv: Option<PathBuf>,
+
+    /// output file for the domain CA certificates (PEM) from EST /cacerts
+    #[structopt(long, parse(from_os_str))]
+    pub trust_store: Option<PathBuf>,
+
+    /// where to keep the domain trust anchor the voucher pinned (PEM),
+    /// which the trust store must still chain to when renewing
+    #[structopt(long, parse(from_os_str))]
+    pub pinned_domain: Option<PathBuf>,
+
+    /// have the registrar generate the LDevID key (EST serverkeygen),
+    /// which it also does when csrattrs asks for it.  Needs an RSA IDevID.
+    #[structopt(default_value = "false", long, parse(try_from_str))]
+    pub server_keygen: bool,
+
+    /// challengePassword for the LDevID CSR, if the registrar asks for one
+    #[structopt(long)]
+    pub challenge_password: Option<String>,
+
+    /// registrar identity in the voucher-request: cert, pubk or pubk-sha256
+    #[structopt(default_value = "cert", long)]
+    pub proximity: ProximityForm,
+
+    /// accept vouchers without a nonce, for pre-provisioned devices
+    #[structopt(default_value = "false", long, parse(try_from_str))]
+    pub allow_nonceless: bool,
+
+    /// voucher format to offer first: cose, cms or jws.  The others are
+    /// tried if the registrar does not accept it.
+    #[structopt(default_value = "cose", long)]
+    pub voucher_format: VoucherFormat,
+
+    /// keep running once enrolled, and renew the LDevID with EST
+    /// simplereenroll before it expires
+    #[structopt(default_value = "false", long, parse(try_from_str))]
+    pub renew: bool,
+
+    /// fraction of the LDevID lifetime after which it is renewed, in (0,1]
+    #[structopt(default_value = "0.75", long, parse(try_from_str = parse_fraction))]
+    pub renew_fraction: f64,
+}
+
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => Ok(fraction),
+        _ => Err(format!("{} is not a fraction in (0,1]", s)),
+    }
 }
 
 #[cfg(test)]
@@ -51,7 +160,11 @@ pub mod tests {
     fn test_parse_args() -> Result<(), std::io::Error> {
         assert_eq!(BootstrapOptions {
             debug_bootstrap: true,
-            registrar: None, idevid_cert: None, idevid_priv: None, ldevid_cert: None
+            registrar: None, idevid_cert: None, idevid_priv: None, masa_cert: None, ldevid_cert: None, ldevid_priv: None,
+            trust_store: None, pinned_domain: None, server_keygen: false, challenge_password: None,
+            proximity: ProximityForm::Cert, allow_nonceless: false,
+            voucher_format: VoucherFormat::Cose,
+            renew: false, renew_fraction: 0.75
         }, BootstrapOptions::from_iter(&["--debug-bootstrap=true"]));
 
         Ok(())
@@ -63,11 +176,24 @@ pub mod tests {
         assert_eq!(BootstrapOptions {
             debug_bootstrap: false,
             registrar: Some(Url::parse("https://example.com/brski/rv").unwrap()),
-            idevid_cert: None, idevid_priv: None, ldevid_cert: None
+            idevid_cert: None, idevid_priv: None, masa_cert: None, ldevid_cert: None, ldevid_priv: None,
+            trust_store: None, pinned_domain: None, server_keygen: false, challenge_password: None,
+            proximity: ProximityForm::Cert, allow_nonceless: false,
+            voucher_format: VoucherFormat::Cose,
+            renew: false, renew_fraction: 0.75
         }, BootstrapOptions::from_iter(&["--registrar=https://example.com/brski/rv"]));
 
         Ok(())
     }
+
+    #[test]
+    fn renew_fraction_range() {
+        assert_eq!(parse_fraction("0.75"), Ok(0.75));
+        assert_eq!(parse_fraction("1"), Ok(1.0));
+        for bad in &["0", "-0.5", "1.5", "NaN", "most"] {
+            assert!(parse_fraction(bad).is_err());
+        }
+    }
 }
 
 
@@ -0,0 +1,372 @@
+/*
+ * Copyright [2021] <mcr@sandelman.ca>
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+ *
+ */
+
+// A minimal DER reader, just enough to walk X.509 and CMS structures that
+// mbedtls does not give us direct access to, and the writer to match.
+
+use std::fmt;
+
+use crate::json_voucher;
+
+pub const TAG_INTEGER:          u8 = 0x02;
+pub const TAG_BIT_STRING:       u8 = 0x03;
+pub const TAG_OCTET_STRING:     u8 = 0x04;
+pub const TAG_NULL:             u8 = 0x05;
+pub const TAG_OID:              u8 = 0x06;
+pub const TAG_UTF8_STRING:      u8 = 0x0c;
+pub const TAG_PRINTABLE_STRING: u8 = 0x13;
+pub const TAG_IA5_STRING:       u8 = 0x16;
+pub const TAG_UTC_TIME:         u8 = 0x17;
+pub const TAG_GENERALIZED_TIME: u8 = 0x18;
+pub const TAG_SEQUENCE:         u8 = 0x30;
+pub const TAG_SET:              u8 = 0x31;
+
+/// context specific, constructed tag [n]
+pub const fn tag_context(n: u8) -> u8 {
+    0xa0 | n
+}
+
+// X520SerialNumber, 2.5.4.5
+pub const OID_SERIAL_NUMBER: &[u8] = &[0x55, 0x04, 0x05];
+// id-Ed25519, 1.3.101.112
+pub const OID_ED25519:       &[u8] = &[0x2b, 0x65, 0x70];
+// id-sha256, id-sha384, id-sha512, 2.16.840.1.101.3.4.2.{1,2,3}
+pub const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+pub const OID_SHA384: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+pub const OID_SHA512: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+// ecdsa-with-SHA256, -SHA384, -SHA512, 1.2.840.10045.4.3.{2,3,4}
+pub const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+pub const OID_ECDSA_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+pub const OID_ECDSA_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04];
+// rsaEncryption 1.2.840.113549.1.1.1, sha256WithRSAEncryption 1.2.840.113549.1.1.11
+pub const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+pub const OID_RSA_SHA256:     &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+// id-mgf1 1.2.840.113549.1.1.8, id-RSASSA-PSS 1.2.840.113549.1.1.10
+pub const OID_MGF1:           &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x08];
+pub const OID_RSASSA_PSS:     &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0a];
+// id-ecPublicKey 1.2.840.10045.2.1, and the named curves
+pub const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+pub const OID_SECP256R1:     &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+pub const OID_SECP384R1:     &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+pub const OID_SECP521R1:     &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x23];
+// PKCS#9 challengePassword 1.2.840.113549.1.9.7, extensionRequest 1.2.840.113549.1.9.14
+pub const OID_CHALLENGE_PASSWORD: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x07];
+pub const OID_EXTENSION_REQUEST:  &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+// id-ce-subjectAltName, 2.5.29.17
+pub const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+// id-on-AcpNodeName (RFC8994), 1.3.6.1.5.5.7.8.10
+pub const OID_ACP_NODE_NAME:    &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x08, 0x0a];
+// id-aa-decryptKeyID 1.2.840.113549.1.9.16.2.37, id-aa-asymmDecryptKeyID ...2.54 (RFC7030)
+pub const OID_DECRYPT_KEY_ID:       &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x02, 0x25];
+pub const OID_ASYMM_DECRYPT_KEY_ID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x02, 0x36];
+
+#[derive(PartialEq, Debug)]
+pub enum Asn1Error {
+    Truncated,
+    BadLength,
+    UnexpectedTag { expected: u8, found: u8 },
+}
+
+impl fmt::Display for Asn1Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Asn1Error::Truncated => {
+                write!(f, "DER input truncated")
+            },
+            Asn1Error::BadLength => {
+                write!(f, "DER length not supported")
+            },
+            Asn1Error::UnexpectedTag { expected, found } => {
+                write!(f, "DER tag 0x{:02x} found, expected 0x{:02x}", found, expected)
+            }
+        }
+    }
+}
+
+/// one Tag-Length-Value item, borrowed from the input buffer.
+/// `raw` is the complete encoding, including tag and length.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Tlv<'a> {
+    pub tag:   u8,
+    pub value: &'a [u8],
+    pub raw:   &'a [u8],
+}
+
+impl<'a> Tlv<'a> {
+    pub fn reader(&self) -> DerReader<'a> {
+        DerReader::new(self.value)
+    }
+}
+
+pub struct DerReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> DerReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        DerReader { buf: buf }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn peek_tag(&self) -> Option<u8> {
+        self.buf.get(0).copied()
+    }
+
+    pub fn read(&mut self) -> Result<Tlv<'a>, Asn1Error> {
+        let tag = *self.buf.get(0).ok_or(Asn1Error::Truncated)?;
+        if tag & 0x1f == 0x1f {
+            // high tag number form, never used by anything we parse
+            return Err(Asn1Error::BadLength);
+        }
+
+        let first = *self.buf.get(1).ok_or(Asn1Error::Truncated)? as usize;
+        let (len, header_len) = if first < 0x80 {
+            (first, 2)
+        } else {
+            let count = first & 0x7f;
+            if count == 0 || count > 4 {
+                return Err(Asn1Error::BadLength);
+            }
+            let bytes = self.buf.get(2..2 + count).ok_or(Asn1Error::Truncated)?;
+            if bytes[0] == 0 {
+                return Err(Asn1Error::BadLength);
+            }
+            let len = bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize);
+            if len < 0x80 {
+                return Err(Asn1Error::BadLength);
+            }
+            (len, 2 + count)
+        };
+
+        let end = header_len.checked_add(len).ok_or(Asn1Error::BadLength)?;
+        if end > self.buf.len() {
+            return Err(Asn1Error::Truncated);
+        }
+
+        let tlv = Tlv {
+            tag:   tag,
+            value: &self.buf[header_len..end],
+            raw:   &self.buf[..end],
+        };
+        self.buf = &self.buf[end..];
+        Ok(tlv)
+    }
+
+    pub fn expect(&mut self, tag: u8) -> Result<Tlv<'a>, Asn1Error> {
+        let tlv = self.read()?;
+        if tlv.tag != tag {
+            return Err(Asn1Error::UnexpectedTag { expected: tag, found: tlv.tag });
+        }
+        Ok(tlv)
+    }
+
+    pub fn read_optional(&mut self, tag: u8) -> Result<Option<Tlv<'a>>, Asn1Error> {
+        if self.peek_tag() == Some(tag) {
+            Ok(Some(self.read()?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn push_length(out: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        out.push(len as u8);
+        return;
+    }
+    let bytes = (len as u32).to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    out.push(0x80 | (4 - skip) as u8);
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+/// encode one TLV, with a minimal DER length
+pub fn der_tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(value.len() + 6);
+    out.push(tag);
+    push_length(&mut out, value.len());
+    out.extend_from_slice(value);
+    out
+}
+
+/// a constructed TLV made of already encoded parts
+pub fn der_constructed(tag: u8, parts: &[&[u8]]) -> Vec<u8> {
+    der_tlv(tag, &parts.concat())
+}
+
+/// the pieces of an X.509 certificate that we need to look at.
+pub struct CertificateParts<'a> {
+    pub tbs:      Tlv<'a>,
+    pub serial:   Tlv<'a>,
+    pub issuer:   Tlv<'a>,
+    pub validity: Tlv<'a>,
+    pub subject:  Tlv<'a>,
+    pub spki:     Tlv<'a>,
+    pub extensions: Option<Tlv<'a>>,
+}
+
+pub fn parse_certificate(der: &[u8]) -> Result<CertificateParts, Asn1Error> {
+    let cert = DerReader::new(der).expect(TAG_SEQUENCE)?;
+    let tbs  = cert.reader().expect(TAG_SEQUENCE)?;
+
+    let mut fields = tbs.reader();
+    fields.read_optional(tag_context(0))?;            // version
+    let serial   = fields.expect(TAG_INTEGER)?;
+    fields.expect(TAG_SEQUENCE)?;                     // signature algorithm
+    let issuer   = fields.expect(TAG_SEQUENCE)?;
+    let validity = fields.expect(TAG_SEQUENCE)?;
+    let subject  = fields.expect(TAG_SEQUENCE)?;
+    let spki     = fields.expect(TAG_SEQUENCE)?;
+    fields.read_optional(0x81)?;                      // issuerUniqueID
+    fields.read_optional(0x82)?;                      // subjectUniqueID
+    let extensions = fields.read_optional(tag_context(3))?;
+
+    Ok(CertificateParts {
+        tbs, serial, issuer, validity, subject, spki, extensions
+    })
+}
+
+/// look through a Name for the first AttributeTypeAndValue with the given OID,
+/// and return the value.
+pub fn find_name_attribute<'a>(name: &Tlv<'a>, oid: &[u8]) -> Result<Option<Tlv<'a>>, Asn1Error> {
+    let mut rdns = name.reader();
+    while !rdns.is_empty() {
+        let mut atvs = rdns.expect(TAG_SET)?.reader();
+        while !atvs.is_empty() {
+            let mut atv = atvs.expect(TAG_SEQUENCE)?.reader();
+            let atype = atv.expect(TAG_OID)?;
+            if atype.value == oid {
+                return Ok(Some(atv.read()?));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// UTCTime or GeneralizedTime, as RFC5280 section 4.1.2.5 restricts them:
+/// seconds, no fraction, and Z
+pub fn parse_time(time: &Tlv) -> Option<u64> {
+    let text = std::str::from_utf8(time.value).ok()?;
+    if !text.is_ascii() {
+        return None;
+    }
+    let full = match time.tag {
+        TAG_UTC_TIME if text.len() == 13 => {
+            // RFC5280: YY of 50 and up is 19YY
+            let century = if text < "50" { "20" } else { "19" };
+            format!("{}{}", century, text)
+        },
+        TAG_GENERALIZED_TIME if text.len() == 15 => text.to_string(),
+        _ => return None,
+    };
+    if !full.ends_with('Z') {
+        return None;
+    }
+    json_voucher::parse_date_time(&format!("{}-{}-{}T{}:{}:{}Z",
+                                           &full[0..4], &full[4..6], &full[6..8],
+                                           &full[8..10], &full[10..12], &full[12..14]))
+}
+
+/// notBefore and notAfter of a certificate
+pub fn validity(parts: &CertificateParts) -> Option<(u64, u64)> {
+    let mut times = parts.validity.reader();
+    let not_before = parse_time(&times.read().ok()?)?;
+    let not_after  = parse_time(&times.read().ok()?)?;
+    Some((not_before, not_after))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // SEQUENCE { SET { SEQUENCE { OID 2.5.4.3, UTF8String "pledge" } },
+    //            SET { SEQUENCE { OID 2.5.4.5, PrintableString "00-D0-E5-F2-00-02" } } }
+    static NAME: &[u8] = &[
+        0x30, 0x2d,
+          0x31, 0x0f, 0x30, 0x0d, 0x06, 0x03, 0x55, 0x04, 0x03,
+                      0x0c, 0x06, b'p', b'l', b'e', b'd', b'g', b'e',
+          0x31, 0x1a, 0x30, 0x18, 0x06, 0x03, 0x55, 0x04, 0x05,
+                      0x13, 0x11, b'0', b'0', b'-', b'D', b'0', b'-', b'E', b'5', b'-',
+                                  b'F', b'2', b'-', b'0', b'0', b'-', b'0', b'2',
+    ];
+
+    #[test]
+    fn find_serial_number() {
+        let name = DerReader::new(NAME).expect(TAG_SEQUENCE).unwrap();
+        let sn = find_name_attribute(&name, OID_SERIAL_NUMBER).unwrap().unwrap();
+        assert_eq!(sn.tag, TAG_PRINTABLE_STRING);
+        assert_eq!(sn.value, b"00-D0-E5-F2-00-02");
+    }
+
+    #[test]
+    fn missing_attribute() {
+        let name = DerReader::new(&NAME[..19]).read();
+        assert_eq!(name, Err(Asn1Error::Truncated));
+
+        let mut short = NAME[..19].to_vec();
+        short[1] = 0x11;
+        let name = DerReader::new(&short).expect(TAG_SEQUENCE).unwrap();
+        assert_eq!(find_name_attribute(&name, OID_SERIAL_NUMBER), Ok(None));
+    }
+
+    #[test]
+    fn write_lengths() {
+        assert_eq!(der_tlv(TAG_OCTET_STRING, &[0xaa; 3]), vec![0x04, 0x03, 0xaa, 0xaa, 0xaa]);
+        assert_eq!(&der_tlv(TAG_OCTET_STRING, &[0u8; 0x80])[..3], &[0x04, 0x81, 0x80]);
+        assert_eq!(&der_tlv(TAG_OCTET_STRING, &[0u8; 0x1234])[..4], &[0x04, 0x82, 0x12, 0x34]);
+
+        let seq = der_constructed(TAG_SEQUENCE, &[&der_tlv(TAG_NULL, &[]), NAME]);
+        let mut fields = DerReader::new(&seq).expect(TAG_SEQUENCE).unwrap().reader();
+        assert_eq!(fields.expect(TAG_NULL).unwrap().value, &[] as &[u8]);
+        assert_eq!(fields.expect(TAG_SEQUENCE).unwrap().raw, NAME);
+        assert!(fields.is_empty());
+    }
+
+    #[test]
+    fn certificate_times() {
+        let utc = der_tlv(TAG_UTC_TIME, b"210102030405Z");
+        let time = DerReader::new(&utc).read().unwrap();
+        assert_eq!(parse_time(&time), Some(1609556645));
+
+        let utc = der_tlv(TAG_UTC_TIME, b"991231235959Z");
+        let time = DerReader::new(&utc).read().unwrap();
+        assert_eq!(parse_time(&time), Some(946684799));
+
+        let generalized = der_tlv(TAG_GENERALIZED_TIME, b"99991231235959Z");
+        let time = DerReader::new(&generalized).read().unwrap();
+        assert_eq!(parse_time(&time), Some(253402300799));
+
+        let local = der_tlv(TAG_GENERALIZED_TIME, b"20210102030405+");
+        let time = DerReader::new(&local).read().unwrap();
+        assert_eq!(parse_time(&time), None);
+
+        // valid UTF-8, but a character straddles the digit boundaries
+        let accent = der_tlv(TAG_UTC_TIME, "2101020é0405Z".as_bytes());
+        let time = DerReader::new(&accent).read().unwrap();
+        assert_eq!(parse_time(&time), None);
+    }
+}
+
+/*
+ * Local Variables:
+ * mode: rust
+ * compile-command: "cd .. && cargo build"
+ * End:
+ */
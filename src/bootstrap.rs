@@ -21,7 +21,6 @@ use std::net::{SocketAddr};
 use std::net::IpAddr;
 use std::net::TcpStream;
 use std::fmt;
-use std::sync::Arc;
 use std::time::Duration;
 use ureq::{Error, ErrorKind, TlsConnector};
 
@@ -35,26 +34,51 @@ use dns_lookup::{lookup_host};
 use url::Url;
 use http::uri::{Builder, Authority};
 use crate::mbedtls_connector;
-
-use crate::custom_voucher::{CustomVoucher as Voucher};
-use minerva_voucher::{attr::*, SignatureAlgorithm, Sign};
+use crate::https::Session;
+use crate::support_rand;
+use crate::idevid::{IDevID, IDevIDError};
+use crate::args::{BootstrapOptions, ProximityForm, VoucherFormat};
+use crate::voucher_validation::{self, Expectations, PinnedDomain, VoucherVerdict};
+use crate::asn1;
+use crate::est::{self, EstError};
+use crate::csr::{self, CsrAttributes};
+use crate::telemetry::{self, StatusEncoding, StatusReport};
+use mbedtls::pk::Pk;
+use std::path::PathBuf;
+
+use crate::custom_voucher::{self, CustomVoucher as Voucher, VoucherAlgorithm};
+use minerva_voucher::{attr::*, SignatureAlgorithm, Sign, VoucherError};
+use std::convert::TryFrom;
 
 use mbedtls::rng::OsEntropy;
 //use mbedtls::rng::CtrDrbg;
 //use mbedtls::ssl::config::{Endpoint, Preset, Transport, AuthMode};
 use mbedtls::ssl::{Config, Context};
+use mbedtls::hash as mbedtls_hash;
 //use mbedtls::x509::Certificate;
 //use mbedtls::Result as TlsResult;
 
 //use ureq::minerva;
-static KEY_PEM_F2_00_02: &[u8] = &[0u8]; // dummy
 
 use http::Method;
 
+pub static VOUCHER_COSE_CONTENT_TYPE: &str = "application/voucher-cose+cbor";
+pub static VOUCHER_CMS_CONTENT_TYPE:  &str = "application/voucher-cms+json";
+pub static VOUCHER_JWS_CONTENT_TYPE:  &str = "application/voucher-jws+json";
+
+// RFC8995 does not set a size, this is what most registrars send
+pub const NONCE_LEN: usize = 16;
+
 #[derive(PartialEq, Debug)]
 pub struct JoinProxyInfo {
     url:  Url,
-    addrs: VecDeque<SocketAddr>
+    addrs: VecDeque<SocketAddr>,
+    // nonce sent in the voucher-request of the current attempt
+    nonce: Option<Vec<u8>>,
+    // domain trust anchor from the accepted voucher, used for EST
+    pinned_domain: Option<PinnedDomain>,
+    // voucher format this registrar has accepted
+    voucher_format: Option<VoucherFormat>
 }
 
 pub fn init_psa_crypto() {
@@ -64,9 +88,82 @@ pub fn init_psa_crypto() {
     psa_crypto::initialized().unwrap();
 }
 
+/// what this device brings to each join proxy it tries
+pub struct Pledge {
+    pub idevid:    IDevID,
+    pub masa_anchor: Vec<u8>,
+    pub proximity: ProximityForm,
+    pub allow_nonceless: bool,
+    pub voucher_format: VoucherFormat,
+    pub trust_store: Option<PathBuf>,
+    pub pinned_domain: Option<PathBuf>,
+    pub challenge_password: Option<String>,
+    pub ldevid_cert: PathBuf,
+    pub ldevid_priv: PathBuf,
+    pub server_keygen: bool,
+}
+
+impl Pledge {
+    pub fn from_options(options: &BootstrapOptions) -> Result<Pledge, String> {
+        let idevid = IDevID::load(options).map_err(|e| e.to_string())?;
+        // the registrar wants the IDevID as TLS client certificate, there
+        // is no point in starting without one
+        idevid.tls_key().map_err(|e| e.to_string())?;
+        println!("IDevID serial-number: {}", String::from_utf8_lossy(&idevid.serial_number));
+
+        let masa_path = options.masa_cert.as_ref()
+            .ok_or("no MASA trust anchor configured, use --masa-cert".to_string())?;
+        let masa_anchor = std::fs::read(masa_path)
+            .map_err(|e| format!("can not read MASA trust anchor: {}", e))?;
+
+        // without both, an issued LDevID would be thrown away
+        let (ldevid_cert, ldevid_priv) = match (&options.ldevid_cert, &options.ldevid_priv) {
+            (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+            _ => return Err("no place for the LDevID, use --ldevid-cert and --ldevid-priv".to_string()),
+        };
+
+        // the registrar encrypts the key it makes to the IDevID, and only
+        // RSA (KeyTrans) recipients can decrypt it here
+        if options.server_keygen && !idevid.can_receive_server_key() {
+            return Err("--serverkeygen needs an RSA IDevID, EC and Ed25519 keys would be sent KeyAgree recipients, which are not supported".to_string());
+        }
+
+        Ok(Pledge {
+            idevid:    idevid,
+            masa_anchor: masa_anchor,
+            proximity: options.proximity,
+            allow_nonceless: options.allow_nonceless,
+            voucher_format: options.voucher_format,
+            trust_store: options.trust_store.clone(),
+            pinned_domain: options.pinned_domain.clone(),
+            challenge_password: options.challenge_password.clone(),
+            ldevid_cert: ldevid_cert,
+            ldevid_priv: ldevid_priv,
+            server_keygen: options.server_keygen,
+        })
+    }
+}
+
+/// the new LDevID, as much of it as signing the enrollstatus needs
+pub struct Enrolled {
+    pub cert_der: Vec<u8>,
+    pub key_pem:  Vec<u8>,
+    pub sig_alg:  Option<VoucherAlgorithm>,
+}
+
 // Custom error for JoinProxyInfo.
+#[derive(Debug)]
 pub enum JoinProxyInfoError {
     NoCertificateFound,
+    BadRegistrarCertificate,
+    RandomFailed(mbedtls::Error),
+    TlsSetupFailed(mbedtls::Error),
+    BadIDevID(IDevIDError),
+    VoucherRejected(VoucherVerdict),
+    IoError(std::io::Error),
+    VoucherRequestRejected(u16),
+    BadVoucher(VoucherError),
+    EstFailed(EstError),
     UreqError(ureq::Error),
     NotImplementedYet
 }
@@ -78,22 +175,32 @@ impl fmt::Display for JoinProxyInfoError {
             JoinProxyInfoError::NoCertificateFound => {
                 write!(f, "No Certificate Found")
             },
-            JoinProxyInfoError::NotImplementedYet => {
-                write!(f, "No implementation yet!")
+            JoinProxyInfoError::BadRegistrarCertificate => {
+                write!(f, "Registrar Certificate not usable")
             },
-            JoinProxyInfoError::UreqError(error) => {
-                write!(f, "UREQ error {}", error)
-            }
-        }
-    }
-}
-
-// Implement std::fmt::Debug for AppError
-impl fmt::Debug for JoinProxyInfoError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            JoinProxyInfoError::NoCertificateFound => {
-                write!(f, "No Certificate Found")
+            JoinProxyInfoError::RandomFailed(error) => {
+                write!(f, "Random generator failed {}", error)
+            },
+            JoinProxyInfoError::TlsSetupFailed(error) => {
+                write!(f, "IDevID not usable as TLS client certificate {}", error)
+            },
+            JoinProxyInfoError::BadIDevID(error) => {
+                write!(f, "{}", error)
+            },
+            JoinProxyInfoError::VoucherRejected(verdict) => {
+                write!(f, "Voucher rejected: {}", verdict)
+            },
+            JoinProxyInfoError::IoError(error) => {
+                write!(f, "IO error {}", error)
+            },
+            JoinProxyInfoError::VoucherRequestRejected(status) => {
+                write!(f, "Voucher-request rejected with status {}", status)
+            },
+            JoinProxyInfoError::BadVoucher(error) => {
+                write!(f, "Voucher not parsed {:?}", error)
+            },
+            JoinProxyInfoError::EstFailed(error) => {
+                write!(f, "EST failed: {}", error)
             },
             JoinProxyInfoError::NotImplementedYet => {
                 write!(f, "No implementation yet!")
@@ -104,20 +211,102 @@ impl fmt::Debug for JoinProxyInfoError {
         }
     }
 }
+
 impl From<ureq::Error> for JoinProxyInfoError {
     fn from(kind: ureq::Error) -> Self {
         Self::UreqError(kind)
     }
 }
+impl From<std::io::Error> for JoinProxyInfoError {
+    fn from(kind: std::io::Error) -> Self {
+        Self::IoError(kind)
+    }
+}
+impl From<EstError> for JoinProxyInfoError {
+    fn from(kind: EstError) -> Self {
+        Self::EstFailed(kind)
+    }
+}
+
+/*
+ * RFC8995 section 5.2: the registrar certificate seen in the provisional
+ * TLS session goes into the voucher-request, so that the MASA can bind the
+ * voucher to this registrar.
+ */
+fn proximity_attr(form: ProximityForm, registrar_cert: &[u8]) -> Result<Attr, JoinProxyInfoError> {
+    if form == ProximityForm::Cert {
+        return Ok(Attr::ProximityRegistrarCert(registrar_cert.to_vec()));
+    }
+
+    let spki = asn1::parse_certificate(registrar_cert)
+        .or(Err(JoinProxyInfoError::BadRegistrarCertificate))?
+        .spki.raw;
+
+    match form {
+        ProximityForm::Pubk => Ok(Attr::ProximityRegistrarPubk(spki.to_vec())),
+        _ => {
+            let mut digest = vec![0u8; 32];
+            mbedtls_hash::Md::hash(mbedtls_hash::Type::Sha256, spki, &mut digest)
+                .or(Err(JoinProxyInfoError::BadRegistrarCertificate))?;
+            Ok(Attr::ProximityRegistrarPubkSha256(digest))
+        }
+    }
+}
+
+pub fn content_type(format: VoucherFormat) -> &'static str {
+    match format {
+        VoucherFormat::Cose    => VOUCHER_COSE_CONTENT_TYPE,
+        VoucherFormat::CmsJson => VOUCHER_CMS_CONTENT_TYPE,
+        VoucherFormat::JwsJson => VOUCHER_JWS_CONTENT_TYPE,
+    }
+}
+
+/// the voucher format of a Content-Type header, parameters ignored
+pub fn format_of(content_type: &str) -> Option<VoucherFormat> {
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    VoucherFormat::ALL.iter().copied()
+        .find(|format| media_type.eq_ignore_ascii_case(self::content_type(*format)))
+}
+
+/// any format is acceptable back, the one sent is preferred
+fn accept_header(format: VoucherFormat) -> String {
+    let mut accept = content_type(format).to_string();
+    for other in format.preference().into_iter().skip(1) {
+        accept.push_str(&format!(", {};q=0.5", content_type(other)));
+    }
+    accept
+}
+
+fn sign_voucher_request(vrq: &mut Voucher, idevid: &IDevID, format: VoucherFormat) -> Result<Vec<u8>, JoinProxyInfoError> {
+    let signed = match format {
+        VoucherFormat::Cose => vrq.sign_as(&idevid.key_pem, idevid.sig_alg),
+        VoucherFormat::CmsJson => vrq.sign_cms(&idevid.key_pem, &idevid.cert_der, idevid.sig_alg),
+        VoucherFormat::JwsJson => vrq.sign_jws(&idevid.key_pem, &idevid.cert_der, idevid.sig_alg),
+    };
+    signed.map_err(JoinProxyInfoError::BadVoucher)?;
+
+    vrq.serialize().map_err(JoinProxyInfoError::BadVoucher)
+}
+
+/*
+ * RFC8995 section 5.1: the pledge authenticates with its IDevID, and takes
+ * whatever the registrar presents, until the voucher says who it should be.
+ */
+fn provisional_connector(idevid: &IDevID) -> Result<mbedtls_connector::MbedTlsConnector, JoinProxyInfoError> {
+    let mode = mbedtls::ssl::config::AuthMode::None;
+    let key = idevid.tls_key().map_err(JoinProxyInfoError::BadIDevID)?;
+    mbedtls_connector::MbedTlsConnector::with_identity(mode, &[], &idevid.chain, key)
+        .map_err(JoinProxyInfoError::TlsSetupFailed)
+}
 
 impl JoinProxyInfo {
     fn connect_one(self: &mut Self,
+                   pledge: &Pledge,
                    addr:   SocketAddr) -> Result<(), JoinProxyInfoError> {
 
         let mut _buf = [0u8; 256];
-        let connector = Arc::new(mbedtls_connector::MbedTlsConnector::new(mbedtls::ssl::config::AuthMode::None));
+        let connector = provisional_connector(&pledge.idevid)?;
 
-        let hostname = addr.ip().to_string();
         let authority = Authority::from_sockaddr(addr).unwrap();
         println!("using hostname: {:?}", authority.to_string());
         let uri = Builder::new()
@@ -127,87 +316,297 @@ impl JoinProxyInfo {
             .build()
             .unwrap();
 
-        let agent = ureq::builder()
-            .tls_connector(connector.clone())
-            .timeout_connect(Duration::from_secs(5))
-            .timeout(Duration::from_secs(20))
-            .build();
-
-        /* establish the connection */
-        let conn = TcpStream::connect(addr).unwrap();
-
-        /* do the TLS bits */
-        let connbox = Box::new(conn);
-        let _https_stream = connector.connect(&hostname, connbox)?;
-        let _request = agent.request(&"POST".to_string(), &uri.to_string());
-
-        /* now pull the certificate out of the stream */
-        //let certificate = https_stream.get_peer_certificate().unwrap();
-        let _cert = {
-            let mbedtls_context    = connector.context.lock().unwrap();
-            let certificate_list   = mbedtls_context.peer_cert().unwrap();
-            //let mut num = 0;
-            let mut cert1: Option<mbedtls::alloc::Box<mbedtls::x509::Certificate>> = None;
-
-            if let Some(certificates) = certificate_list {
-                // only use first certificate returned
-                for cert in certificates {
-                    match cert1 {
-                        None => { cert1 = Some(cert.clone()) },
-                        _ => {}
-                    }
-                    //println!("[{}] cert: {:?}", num, cert.clone());
-                    //num = num + 1;
-                }
-            } else {
-                return Err(JoinProxyInfoError::NoCertificateFound);
-            }
-
-            // now we have the peer certificate copied into cert1.
-            println!("cert1: {:?}", cert1);
-
-            cert1
-        };
+        /* do the TLS bits, and pull the certificates out of the handshake */
+        let session = Session::new(connector, &uri.authority().unwrap().to_string());
+        let peer_chain = session.handshake(addr)?;
+        let registrar_cert = peer_chain.first()
+            .ok_or(JoinProxyInfoError::NoCertificateFound)?
+            .clone();
 
         { //--------
+            let idevid = &pledge.idevid;
+            let nonce = support_rand::random_bytes(NONCE_LEN)
+                .map_err(JoinProxyInfoError::RandomFailed)?;
+            self.nonce = Some(nonce.clone());
+
             let mut vrq = Voucher::new_vrq();
 
             vrq.set(Attr::Assertion(Assertion::Proximity))
-                .set(Attr::CreatedOn(1599086034))
-                .set(Attr::SerialNumber(b"00-D0-E5-F2-00-02".to_vec()));
+                .set(Attr::Nonce(nonce))
+                .set(Attr::CreatedOn(voucher_validation::now()))
+                .set(Attr::SerialNumber(idevid.serial_number.clone()))
+                .set(proximity_attr(pledge.proximity, &registrar_cert)?);
 
             // This is required when the `Sign` trait is backed by mbedtls v3.
             init_psa_crypto();
 
-            vrq.sign(KEY_PEM_F2_00_02, SignatureAlgorithm::ES256).unwrap();
+            /*
+             * now send the voucher-request on the same TLS session.
+             * Start with the format this registrar took before, or the
+             * configured one, and fall back to the others if it is refused.
+             */
+            let first = self.voucher_format.unwrap_or(pledge.voucher_format);
+            let mut sent = first;
+            let mut resp = None;
+            for format in first.preference() {
+                let body = sign_voucher_request(&mut vrq, idevid, format)?;
+                let accept = accept_header(format);
+                let r = session.request("POST", uri.path(),
+                                        &[("Content-Type", content_type(format)),
+                                          ("Accept",       &accept)],
+                                        &body)?;
+                println!("status code {}", r.status);
+                sent = format;
+                let refused = r.status == 406 || r.status == 415;
+                resp = Some(r);
+                if !refused {
+                    break;
+                }
+                println!("registrar does not take {}", content_type(format));
+            }
+            let resp = resp.unwrap();
+            if !resp.is_success() {
+                return Err(JoinProxyInfoError::VoucherRequestRejected(resp.status));
+            }
+            self.voucher_format = Some(sent);
+
+            // the voucher may come back in either format
+            let format = resp.header("Content-Type").and_then(format_of).unwrap_or(sent);
+            let parsed = match format {
+                VoucherFormat::Cose    => Voucher::try_from(resp.body.as_slice()),
+                VoucherFormat::CmsJson => Voucher::from_cms(&resp.body),
+                VoucherFormat::JwsJson => Voucher::from_jws(&resp.body),
+            };
+            let voucher = match parsed {
+                Ok(voucher) => voucher,
+                Err(error) => {
+                    self.report_voucher_status(pledge, &session, VoucherVerdict::Malformed);
+                    return Err(JoinProxyInfoError::BadVoucher(error));
+                }
+            };
+
+            let verdict = voucher_validation::validate_voucher(&voucher, &Expectations {
+                masa_anchor:     &pledge.masa_anchor,
+                serial_number:   &idevid.serial_number,
+                nonce:           self.nonce.as_deref(),
+                allow_nonceless: pledge.allow_nonceless,
+                now:             voucher_validation::now(),
+            });
+            println!("voucher received: {}", verdict);
+
+            /* now go back and check the registrar we provisionally accepted */
+            let checked = match voucher_validation::pinned_domain(&voucher) {
+                _ if !verdict.is_accepted() => Err(verdict),
+                None => Err(VoucherVerdict::PinnedDomainMissing),
+                Some(pinned) => {
+                    let verdict = voucher_validation::verify_registrar(&pinned, &peer_chain);
+                    if verdict.is_accepted() { Ok(pinned) } else { Err(verdict) }
+                }
+            };
+
+            let verdict = checked.as_ref().err().copied().unwrap_or(VoucherVerdict::Accepted);
+            self.report_voucher_status(pledge, &session, verdict);
+
+            let pinned = match checked {
+                Ok(pinned) => pinned,
+                Err(verdict) => {
+                    // abandon this session, it may be a rogue registrar
+                    drop(session);
+                    return Err(JoinProxyInfoError::VoucherRejected(verdict));
+                }
+            };
+            println!("registrar verified against pinned-domain-cert");
+            if let Some(path) = &pledge.pinned_domain {
+                est::write_atomically(path, pinned.to_pem().as_bytes(), 0o644)?;
+                println!("pinned domain anchor written to {}", path.display());
+            }
+            self.pinned_domain = Some(pinned);
+
+            /* the session is now trusted, continue with EST */
+            let enrolled = self.run_est(pledge, &session);
+            self.report_enroll_status(pledge, &session, &enrolled);
+            enrolled.map(|_| ())
+        }
+    }
+
+    fn run_est(self: &Self,
+               pledge:  &Pledge,
+               session: &Session) -> Result<Enrolled, JoinProxyInfoError> {
+        let cacerts = self.install_cacerts(pledge, session)?;
 
-            let _cbor = vrq.serialize().unwrap();
+        let attrs = est::fetch_csrattrs(session)?;
+        let server_keygen = attrs.server_keygen && pledge.idevid.can_receive_server_key();
+        if attrs.server_keygen && !server_keygen {
+            println!("registrar asks for serverkeygen, which needs an RSA IDevID, making a key here instead");
+        }
+        if pledge.server_keygen || server_keygen {
+            let csr = self.server_keygen_csr(pledge, &attrs)?;
+            self.server_keygen(pledge, session, &csr, &cacerts)
+        } else {
+            let (ldevid_key, csr) = self.request_csr(pledge, &attrs)?;
+            self.enroll(pledge, session, ldevid_key, &csr, &cacerts)
+        }
+    }
+
+    /*
+     * RFC8995 section 5.9.4: signed with the new LDevID if there is one,
+     * otherwise with the IDevID.  The registrar may not answer at all,
+     * which is fine.
+     */
+    fn report_enroll_status(self: &Self,
+                            pledge:   &Pledge,
+                            session:  &Session,
+                            enrolled: &Result<Enrolled, JoinProxyInfoError>) {
+        let idevid = &pledge.idevid;
+        let (report, cert_der, key_pem, sig_alg) = match enrolled {
+            Ok(Enrolled { cert_der, key_pem, sig_alg: Some(sig_alg) }) => {
+                (StatusReport::enrolled(), cert_der, key_pem, *sig_alg)
+            },
+            Ok(_) => (StatusReport::enrolled(), &idevid.cert_der, &idevid.key_pem, idevid.sig_alg),
+            Err(error) => {
+                (StatusReport::enroll_failed(error.to_string()), &idevid.cert_der, &idevid.key_pem, idevid.sig_alg)
+            },
+        };
 
-            return Err(JoinProxyInfoError::NotImplementedYet);
+        let encoding = StatusEncoding::for_voucher_format(self.voucher_format.unwrap_or(pledge.voucher_format));
+        let sent = report.sign(encoding, cert_der, key_pem, sig_alg)
+            .and_then(|signed| telemetry::post_report(session, telemetry::ENROLL_STATUS, signed));
+        match sent {
+            Ok(status) => println!("enrollstatus sent, registrar answered {}", status),
+            Err(error) => println!("enrollstatus: {}", error),
+        }
+    }
 
+    /// RFC8995 section 5.7: tell the registrar what became of the voucher.
+    /// The answer, if any, changes nothing.
+    fn report_voucher_status(self: &Self,
+                             pledge:  &Pledge,
+                             session: &Session,
+                             verdict: VoucherVerdict) {
+        let idevid = &pledge.idevid;
+        let encoding = StatusEncoding::for_voucher_format(self.voucher_format.unwrap_or(pledge.voucher_format));
+        let sent = StatusReport::from_verdict(verdict)
+            .sign(encoding, &idevid.cert_der, &idevid.key_pem, idevid.sig_alg)
+            .and_then(|signed| telemetry::post_report(session, telemetry::VOUCHER_STATUS, signed));
+        match sent {
+            Ok(status) => println!("voucher_status sent, registrar answered {}", status),
+            Err(error) => println!("voucher_status: {}", error),
         }
+    }
 
-        /* print it */
+    /// RFC8995 section 5.9.2: a new key, and a CSR with what the registrar asked for
+    fn request_csr(self: &Self,
+                   pledge: &Pledge,
+                   attrs:  &CsrAttributes) -> Result<(Pk, Vec<u8>), JoinProxyInfoError> {
+        if let Some(name) = attrs.acp_node_name() {
+            println!("registrar assigns ACP node name {}", name);
+        }
 
-        /* now send a request */
-        //brski_request(stream).
-        //let resp = parse_response(agent, req)?;
+        let key_type = attrs.key_type.unwrap_or(csr::DEFAULT_KEY_TYPE);
+        let mut key = csr::generate_key(key_type).map_err(|e| EstError::CsrFailed(e.into()))?;
+        let request = csr::build_csr(&mut key,
+                                     &pledge.idevid.serial_number,
+                                     attrs,
+                                     pledge.challenge_password.as_deref())
+            .map_err(EstError::CsrFailed)?;
+        Ok((key, request))
+    }
 
-        //println!("status code {}", resp.status().as_u16());
-        //println!("response {:?}", resp);
-        //println!("closed");
-        //Ok(())
+    /*
+     * RFC7030 section 4.4.1: the registrar ignores the key and signature
+     * of a serverkeygen CSR.  It is signed with the IDevID key, so that
+     * a device with poor entropy does not make a key just to throw it away.
+     */
+    fn server_keygen_csr(self: &Self,
+                         pledge: &Pledge,
+                         attrs:  &CsrAttributes) -> Result<Vec<u8>, JoinProxyInfoError> {
+        let mut key = pledge.idevid.tls_key().map_err(JoinProxyInfoError::BadIDevID)?;
+        let request = csr::build_csr(&mut key,
+                                     &pledge.idevid.serial_number,
+                                     attrs,
+                                     pledge.challenge_password.as_deref())
+            .map_err(EstError::CsrFailed)?;
+        Ok(request)
     }
 
-    pub fn connect(self: &mut Self) -> Result<(), std::io::Error> {
+    /// RFC8995 section 5.9.3: the LDevID, checked against the CSR key and
+    /// the domain CA before anything is written
+    fn enroll(self: &Self,
+              pledge:  &Pledge,
+              session: &Session,
+              mut key: Pk,
+              csr:     &[u8],
+              cacerts: &[Vec<u8>]) -> Result<Enrolled, JoinProxyInfoError> {
+        let issued = est::enroll(session, est::EST_SIMPLEENROLL, csr)?;
+        let spki = key.write_public_der_vec().map_err(|e| EstError::CsrFailed(e.into()))?;
+        let chain = est::issued_chain(&issued, &spki, cacerts)?;
+        println!("EST simpleenroll: LDevID issued, {} bytes", chain[0].len());
+        self.store_ldevid(pledge, &chain, &mut key)
+    }
+
+    /*
+     * RFC7030 section 4.4: the registrar makes the key, for devices that
+     * can not make a good one.
+     */
+    fn server_keygen(self: &Self,
+                     pledge:  &Pledge,
+                     session: &Session,
+                     csr:     &[u8],
+                     cacerts: &[Vec<u8>]) -> Result<Enrolled, JoinProxyInfoError> {
+        let idevid = &pledge.idevid;
+        let server_key = est::server_keygen(session, csr, cacerts, &idevid.cert_der, &idevid.key_pem)?;
+        let mut key = Pk::from_private_key(&server_key.key_der, None).or(Err(EstError::NoServerKey))?;
+        let spki = key.write_public_der_vec().or(Err(EstError::NoServerKey))?;
+        let chain = est::issued_chain(&server_key.certs, &spki, cacerts)?;
+        println!("EST serverkeygen: LDevID and key issued, {} bytes", chain[0].len());
+        self.store_ldevid(pledge, &chain, &mut key)
+    }
+
+    fn store_ldevid(self: &Self,
+                    pledge: &Pledge,
+                    chain:  &[Vec<u8>],
+                    key:    &mut Pk) -> Result<Enrolled, JoinProxyInfoError> {
+        let key_pem = key.write_private_pem_string().map_err(|e| EstError::CsrFailed(e.into()))?;
+        est::write_ldevid(&pledge.ldevid_cert, &pledge.ldevid_priv, chain, key_pem.as_bytes())?;
+        println!("LDevID written to {} and {}", pledge.ldevid_cert.display(), pledge.ldevid_priv.display());
+        Ok(Enrolled {
+            cert_der: chain[0].clone(),
+            key_pem:  key_pem.into_bytes(),
+            sig_alg:  custom_voucher::signature_algorithm_for(key),
+        })
+    }
+
+    /// RFC8995 section 5.9.1: the domain CA, which the downstream services trust
+    fn install_cacerts(self: &Self,
+                       pledge:  &Pledge,
+                       session: &Session) -> Result<Vec<Vec<u8>>, JoinProxyInfoError> {
+        let pinned = self.pinned_domain.as_ref()
+            .ok_or(JoinProxyInfoError::VoucherRejected(VoucherVerdict::PinnedDomainMissing))?;
+        let cacerts = est::fetch_cacerts(session, pinned)?;
+        println!("EST cacerts: {} certificates, matching the pinned domain", cacerts.len());
+
+        match &pledge.trust_store {
+            Some(path) => {
+                est::write_trust_store(path, &cacerts)?;
+                println!("domain trust store written to {}", path.display());
+            },
+            None => println!("no --trust-store given, domain CA not saved"),
+        }
+        Ok(cacerts)
+    }
+
+    pub fn connect(self: &mut Self, pledge: &Pledge) -> Result<(), std::io::Error> {
 
         while let Some(addr) = self.addrs.pop_front() {
             println!("found address: {:?}", addr.to_string());
-            let tlserr = self.connect_one(addr);
+            let tlserr = self.connect_one(pledge, addr);
 
             // examine tlserr for ECONN refused and try next IP.
             match tlserr {
-                Err(_x) => { return Err(std::io::Error::new(io::ErrorKind::Other, "TLS failed")) }
+                Err(x) => {
+                    println!("join proxy {} failed: {}", addr, x);
+                    return Err(std::io::Error::new(io::ErrorKind::Other, "TLS failed"))
+                }
                 Ok(_x)  => { return Ok(()) }
             }
         }
@@ -252,7 +651,10 @@ impl BootstrapState {
         let hosts = lookup_host(hostname)?;
         self.registrars.send(JoinProxyInfo {
             url:   url,
-            addrs: BootstrapState::addr2sockaddr(hosts, port)
+            addrs: BootstrapState::addr2sockaddr(hosts, port),
+            nonce: None,
+            pinned_domain: None,
+            voucher_format: None
         }).unwrap();
         Ok(())
     }
@@ -264,7 +666,10 @@ impl BootstrapState {
         let hosts = vec![ip];
         self.registrars.send(JoinProxyInfo {
             url:   url,
-            addrs: BootstrapState::addr2sockaddr(hosts, port)
+            addrs: BootstrapState::addr2sockaddr(hosts, port),
+            nonce: None,
+            pinned_domain: None,
+            voucher_format: None
         }).unwrap();
         Ok(())
     }
@@ -299,6 +704,16 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn voucher_content_types() {
+        assert_eq!(format_of("application/voucher-cms+json"), Some(VoucherFormat::CmsJson));
+        assert_eq!(format_of("Application/Voucher-COSE+CBOR; charset=binary"), Some(VoucherFormat::Cose));
+        assert_eq!(format_of("application/voucher-jws+json"), Some(VoucherFormat::JwsJson));
+        assert_eq!(format_of("application/json"), None);
+        assert_eq!(accept_header(VoucherFormat::CmsJson),
+                   "application/voucher-cms+json, application/voucher-cose+cbor;q=0.5, application/voucher-jws+json;q=0.5");
+    }
+
     #[test]
     fn add_bad_registrar_url() {
         let (sender, _receiver) = BootstrapState::channel();
@@ -0,0 +1,249 @@
+/*
+ * Copyright [2021] <mcr@sandelman.ca>
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+ *
+ */
+
+// A small CBOR (RFC8949) codec, for the COSE structures that
+// minerva_voucher does not handle for us.  Only definite lengths.
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum CborValue {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<CborValue>),
+    Map(Vec<(CborValue, CborValue)>),
+    Tag(u64, Box<CborValue>),
+    Bool(bool),
+    Null,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum CborError {
+    Truncated,
+    Unsupported,
+    TrailingBytes,
+}
+
+impl CborValue {
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            CborValue::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            CborValue::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// look up an entry of a map by integer label, as COSE does
+    pub fn map_get(&self, label: i64) -> Option<&CborValue> {
+        match self {
+            CborValue::Map(entries) => entries.iter()
+                .find(|(k, _)| *k == CborValue::Integer(label))
+                .map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+fn encode_head(out: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    if value < 24 {
+        out.push(major | value as u8);
+    } else if value <= 0xff {
+        out.push(major | 24);
+        out.push(value as u8);
+    } else if value <= 0xffff {
+        out.push(major | 25);
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= 0xffff_ffff {
+        out.push(major | 26);
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+pub fn encode_into(out: &mut Vec<u8>, value: &CborValue) {
+    match value {
+        CborValue::Integer(i) if *i >= 0 => encode_head(out, 0, *i as u64),
+        CborValue::Integer(i) => encode_head(out, 1, (-1 - *i) as u64),
+        CborValue::Bytes(b) => {
+            encode_head(out, 2, b.len() as u64);
+            out.extend_from_slice(b);
+        },
+        CborValue::Text(t) => {
+            encode_head(out, 3, t.len() as u64);
+            out.extend_from_slice(t.as_bytes());
+        },
+        CborValue::Array(items) => {
+            encode_head(out, 4, items.len() as u64);
+            for item in items {
+                encode_into(out, item);
+            }
+        },
+        CborValue::Map(entries) => {
+            encode_head(out, 5, entries.len() as u64);
+            for (k, v) in entries {
+                encode_into(out, k);
+                encode_into(out, v);
+            }
+        },
+        CborValue::Tag(tag, inner) => {
+            encode_head(out, 6, *tag);
+            encode_into(out, inner);
+        },
+        CborValue::Bool(false) => out.push(0xf4),
+        CborValue::Bool(true)  => out.push(0xf5),
+        CborValue::Null        => out.push(0xf6),
+    }
+}
+
+pub fn encode(value: &CborValue) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(&mut out, value);
+    out
+}
+
+/// decode exactly one item, which must fill the whole input
+pub fn decode(buf: &[u8]) -> Result<CborValue, CborError> {
+    let (value, used) = decode_item(buf, 0)?;
+    if used != buf.len() {
+        return Err(CborError::TrailingBytes);
+    }
+    Ok(value)
+}
+
+const MAX_DEPTH: usize = 16;
+
+fn decode_item(buf: &[u8], depth: usize) -> Result<(CborValue, usize), CborError> {
+    if depth > MAX_DEPTH {
+        return Err(CborError::Unsupported);
+    }
+
+    let initial = *buf.get(0).ok_or(CborError::Truncated)?;
+    let major = initial >> 5;
+    let info  = initial & 0x1f;
+
+    let (arg, mut pos) = match info {
+        0..=23 => (info as u64, 1),
+        24..=27 => {
+            let n = 1usize << (info - 24);
+            let bytes = buf.get(1..1 + n).ok_or(CborError::Truncated)?;
+            (bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64), 1 + n)
+        },
+        _ => return Err(CborError::Unsupported),
+    };
+
+    let value = match major {
+        0 => {
+            if arg > i64::MAX as u64 { return Err(CborError::Unsupported); }
+            CborValue::Integer(arg as i64)
+        },
+        1 => {
+            if arg > i64::MAX as u64 { return Err(CborError::Unsupported); }
+            CborValue::Integer(-1 - arg as i64)
+        },
+        2 | 3 => {
+            let len = usize::try_from(arg).map_err(|_| CborError::Truncated)?;
+            let end = pos.checked_add(len).ok_or(CborError::Truncated)?;
+            let bytes = buf.get(pos..end).ok_or(CborError::Truncated)?.to_vec();
+            pos = end;
+            if major == 2 {
+                CborValue::Bytes(bytes)
+            } else {
+                CborValue::Text(String::from_utf8(bytes).map_err(|_| CborError::Unsupported)?)
+            }
+        },
+        4 => {
+            let mut items = Vec::new();
+            for _ in 0..arg {
+                let (item, used) = decode_item(&buf[pos..], depth + 1)?;
+                items.push(item);
+                pos += used;
+            }
+            CborValue::Array(items)
+        },
+        5 => {
+            let mut entries = Vec::new();
+            for _ in 0..arg {
+                let (k, used) = decode_item(&buf[pos..], depth + 1)?;
+                pos += used;
+                let (v, used) = decode_item(&buf[pos..], depth + 1)?;
+                pos += used;
+                entries.push((k, v));
+            }
+            CborValue::Map(entries)
+        },
+        6 => {
+            let (inner, used) = decode_item(&buf[pos..], depth + 1)?;
+            pos += used;
+            CborValue::Tag(arg, Box::new(inner))
+        },
+        _ => match info {
+            20 => CborValue::Bool(false),
+            21 => CborValue::Bool(true),
+            22 => CborValue::Null,
+            _  => return Err(CborError::Unsupported),
+        },
+    };
+
+    Ok((value, pos))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let value = CborValue::Tag(18, Box::new(CborValue::Array(vec![
+            CborValue::Bytes(vec![0xa1, 0x01, 0x27]),
+            CborValue::Map(vec![(CborValue::Integer(4), CborValue::Text("kid".to_string()))]),
+            CborValue::Bytes(vec![0u8; 300]),
+            CborValue::Null,
+        ])));
+        let encoded = encode(&value);
+        assert_eq!(&encoded[..3], &[0xd2, 0x84, 0x43]);
+        assert_eq!(decode(&encoded), Ok(value));
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(encode(&CborValue::Integer(-8)), vec![0x27]);
+        assert_eq!(encode(&CborValue::Integer(500)), vec![0x19, 0x01, 0xf4]);
+        assert_eq!(decode(&[0x38, 0x18]), Ok(CborValue::Integer(-25)));
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(decode(&[0x84, 0x01]), Err(CborError::Truncated));
+        assert_eq!(decode(&[0x5a, 0xff, 0xff, 0xff, 0xff, 0x00]), Err(CborError::Truncated));
+        assert_eq!(decode(&[0x01, 0x02]), Err(CborError::TrailingBytes));
+    }
+}
+
+/*
+ * Local Variables:
+ * mode: rust
+ * compile-command: "cd .. && cargo build"
+ * End:
+ */
@@ -0,0 +1,616 @@
+/*
+ * Copyright [2021] <mcr@sandelman.ca>
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+ *
+ */
+
+/*
+ * CMS SignedData (RFC5652), as used for the JSON vouchers and
+ * voucher-requests of RFC8366 section 5.4 and RFC8995 section 3.
+ * One signer, identified by issuer and serial number, and the usual
+ * contentType and messageDigest signed attributes.
+ *
+ * Also just enough EnvelopedData to open the encrypted keys of EST
+ * serverkeygen (RFC7030 section 4.4.2).
+ */
+
+use std::fmt;
+
+use mbedtls::cipher::raw::{CipherId, CipherMode};
+use mbedtls::cipher::{Cipher, Decryption, Traditional};
+use mbedtls::hash as mbedtls_hash;
+use mbedtls::pk::{Pk, ECDSA_MAX_LEN};
+use mbedtls::x509::Certificate;
+
+use crate::asn1::{self, der_constructed, der_tlv, Asn1Error, DerReader};
+use crate::custom_voucher::{use_pss, VoucherAlgorithm};
+use crate::ed25519;
+use crate::support_rand;
+use crate::utils;
+use crate::voucher_validation;
+
+// id-data, 1.2.840.113549.1.7.1
+pub const OID_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01];
+// id-signedData, 1.2.840.113549.1.7.2
+pub const OID_SIGNED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+// id-ct-animaJSONVoucher, 1.2.840.113549.1.9.16.1.40
+pub const OID_ANIMA_JSON_VOUCHER: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x01, 0x28];
+// id-envelopedData, 1.2.840.113549.1.7.3
+pub const OID_ENVELOPED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x03];
+// aes128-CBC and aes256-CBC, 2.16.840.1.101.3.4.1.{2,42}
+const OID_AES128_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x02];
+const OID_AES256_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a];
+// id-contentType and id-messageDigest, 1.2.840.113549.1.9.{3,4}
+const OID_CONTENT_TYPE:   &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x03];
+const OID_MESSAGE_DIGEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x04];
+
+#[derive(Debug)]
+pub enum CmsError {
+    Malformed(Asn1Error),
+    NotSignedData,
+    NotEnvelopedData,
+    NoRecipient,
+    KeyAgreeUnsupported,
+    UnsupportedAlgorithm,
+    BadKey,
+    CryptoFailed(mbedtls::Error),
+}
+
+impl fmt::Display for CmsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CmsError::Malformed(error) => {
+                write!(f, "CMS structure malformed: {}", error)
+            },
+            CmsError::NotSignedData => {
+                write!(f, "CMS content is not SignedData")
+            },
+            CmsError::NotEnvelopedData => {
+                write!(f, "CMS content is not EnvelopedData")
+            },
+            CmsError::NoRecipient => {
+                write!(f, "CMS EnvelopedData has no recipient our key can open")
+            },
+            CmsError::KeyAgreeUnsupported => {
+                write!(f, "CMS EnvelopedData for an EC key (KeyAgreeRecipientInfo) is not supported")
+            },
+            CmsError::UnsupportedAlgorithm => {
+                write!(f, "CMS algorithm not supported")
+            },
+            CmsError::BadKey => {
+                write!(f, "CMS signer key or certificate not usable")
+            },
+            CmsError::CryptoFailed(error) => {
+                write!(f, "CMS signature operation failed {}", error)
+            }
+        }
+    }
+}
+
+impl From<Asn1Error> for CmsError {
+    fn from(error: Asn1Error) -> Self {
+        CmsError::Malformed(error)
+    }
+}
+
+impl From<mbedtls::Error> for CmsError {
+    fn from(error: mbedtls::Error) -> Self {
+        CmsError::CryptoFailed(error)
+    }
+}
+
+pub struct SignerInfo {
+    /// the complete SignerIdentifier
+    pub sid:           Vec<u8>,
+    pub digest_alg:    Vec<u8>,
+    /// the signed attributes, tagged as the SET OF that is signed
+    pub signed_attrs:  Option<Vec<u8>>,
+    pub signature_alg: Vec<u8>,
+    pub signature:     Vec<u8>,
+}
+
+pub struct SignedData {
+    pub content_type: Vec<u8>,
+    pub content:      Vec<u8>,
+    pub certificates: Vec<Vec<u8>>,
+    pub signers:      Vec<SignerInfo>,
+}
+
+fn algorithm_oid(alg_id: &asn1::Tlv) -> Result<Vec<u8>, Asn1Error> {
+    Ok(alg_id.reader().expect(asn1::TAG_OID)?.value.to_vec())
+}
+
+fn algorithm_id(oid: &[u8], null_params: bool) -> Vec<u8> {
+    let oid = der_tlv(asn1::TAG_OID, oid);
+    if null_params {
+        der_constructed(asn1::TAG_SEQUENCE, &[&oid, &der_tlv(asn1::TAG_NULL, &[])])
+    } else {
+        der_constructed(asn1::TAG_SEQUENCE, &[&oid])
+    }
+}
+
+fn digest_type(oid: &[u8]) -> Option<(mbedtls_hash::Type, usize)> {
+    match oid {
+        asn1::OID_SHA256 => Some((mbedtls_hash::Type::Sha256, 32)),
+        asn1::OID_SHA384 => Some((mbedtls_hash::Type::Sha384, 48)),
+        asn1::OID_SHA512 => Some((mbedtls_hash::Type::Sha512, 64)),
+        _ => None,
+    }
+}
+
+fn digest(oid: &[u8], msg: &[u8]) -> Result<(Vec<u8>, mbedtls_hash::Type), CmsError> {
+    let (md_type, len) = digest_type(oid).ok_or(CmsError::UnsupportedAlgorithm)?;
+    let mut out = vec![0u8; len];
+    mbedtls_hash::Md::hash(md_type, msg, &mut out)?;
+    Ok((out, md_type))
+}
+
+impl SignerInfo {
+    fn decode(tlv: asn1::Tlv) -> Result<SignerInfo, Asn1Error> {
+        let mut fields = tlv.reader();
+        fields.expect(asn1::TAG_INTEGER)?;                 // version
+        let sid        = fields.read()?;
+        let digest_alg = fields.expect(asn1::TAG_SEQUENCE)?;
+        let signed_attrs = fields.read_optional(asn1::tag_context(0))?.map(|attrs| {
+            // RFC5652 section 5.4: the signature covers the EXPLICIT SET OF tag
+            let mut set = attrs.raw.to_vec();
+            set[0] = asn1::TAG_SET;
+            set
+        });
+        let signature_alg = fields.expect(asn1::TAG_SEQUENCE)?;
+        let signature     = fields.expect(asn1::TAG_OCTET_STRING)?;
+
+        Ok(SignerInfo {
+            sid:           sid.raw.to_vec(),
+            digest_alg:    algorithm_oid(&digest_alg)?,
+            signed_attrs:  signed_attrs,
+            signature_alg: algorithm_oid(&signature_alg)?,
+            signature:     signature.value.to_vec(),
+        })
+    }
+
+    /// the first value of a signed attribute
+    fn signed_attr(&self, oid: &[u8]) -> Result<Option<Vec<u8>>, Asn1Error> {
+        let attrs = match &self.signed_attrs {
+            Some(attrs) => attrs,
+            None => return Ok(None),
+        };
+        let mut attrs = DerReader::new(attrs).expect(asn1::TAG_SET)?.reader();
+        while !attrs.is_empty() {
+            let mut attr = attrs.expect(asn1::TAG_SEQUENCE)?.reader();
+            if attr.expect(asn1::TAG_OID)?.value == oid {
+                let mut values = attr.expect(asn1::TAG_SET)?.reader();
+                return Ok(Some(values.read()?.value.to_vec()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// what the signature is over, once the signed attributes are checked
+    fn to_be_signed(&self, content_type: &[u8], content: &[u8]) -> Result<Option<Vec<u8>>, CmsError> {
+        let attrs = match &self.signed_attrs {
+            Some(attrs) => attrs,
+            None => return Ok(Some(content.to_vec())),
+        };
+
+        let (expected, _) = digest(&self.digest_alg, content)?;
+        if self.signed_attr(OID_CONTENT_TYPE)?.as_deref() != Some(content_type)
+            || self.signed_attr(OID_MESSAGE_DIGEST)?.as_deref() != Some(expected.as_slice()) {
+            return Ok(None);
+        }
+        Ok(Some(attrs.clone()))
+    }
+}
+
+impl SignedData {
+    pub fn decode(der: &[u8]) -> Result<SignedData, CmsError> {
+        let mut content_info = DerReader::new(der).expect(asn1::TAG_SEQUENCE)?.reader();
+        if content_info.expect(asn1::TAG_OID)?.value != OID_SIGNED_DATA {
+            return Err(CmsError::NotSignedData);
+        }
+        let signed_data = content_info.expect(asn1::tag_context(0))?
+            .reader().expect(asn1::TAG_SEQUENCE)?;
+
+        let mut fields = signed_data.reader();
+        fields.expect(asn1::TAG_INTEGER)?;                 // version
+        fields.expect(asn1::TAG_SET)?;                     // digestAlgorithms
+
+        let mut encap = fields.expect(asn1::TAG_SEQUENCE)?.reader();
+        let content_type = encap.expect(asn1::TAG_OID)?.value.to_vec();
+        // absent in a certs-only message (RFC5751 section 3.6)
+        let content = match encap.read_optional(asn1::tag_context(0))? {
+            Some(econtent) => econtent.reader().expect(asn1::TAG_OCTET_STRING)?.value.to_vec(),
+            None => Vec::new(),
+        };
+
+        let mut certificates = Vec::new();
+        if let Some(certs) = fields.read_optional(asn1::tag_context(0))? {
+            let mut certs = certs.reader();
+            while !certs.is_empty() {
+                certificates.push(certs.expect(asn1::TAG_SEQUENCE)?.raw.to_vec());
+            }
+        }
+        fields.read_optional(asn1::tag_context(1))?;      // crls
+
+        let mut signers = Vec::new();
+        let mut infos = fields.expect(asn1::TAG_SET)?.reader();
+        while !infos.is_empty() {
+            signers.push(SignerInfo::decode(infos.expect(asn1::TAG_SEQUENCE)?)?);
+        }
+
+        Ok(SignedData {
+            content_type: content_type,
+            content:      content,
+            certificates: certificates,
+            signers:      signers,
+        })
+    }
+
+    /*
+     * the anchor (PEM or DER) may be the signing certificate itself, or a
+     * CA that the signing certificate, carried in the SignedData, chains to.
+     */
+    pub fn verify(&self, anchor: &[u8]) -> bool {
+        let anchor = match utils::cert_der(anchor) {
+            Some(anchor) => anchor,
+            None => return false,
+        };
+
+        self.signers.iter().any(|signer| {
+            match self.verify_signer(signer, &anchor) {
+                Ok(valid) => valid,
+                Err(e) => {
+                    println!("CMS signer not verified: {}", e);
+                    false
+                }
+            }
+        })
+    }
+
+    fn verify_signer(&self, signer: &SignerInfo, anchor: &[u8]) -> Result<bool, CmsError> {
+        let tbs = match signer.to_be_signed(&self.content_type, &self.content)? {
+            Some(tbs) => tbs,
+            None => return Ok(false),
+        };
+
+        let signer_cert = self.certificates.iter()
+            .find(|cert| identifies(&signer.sid, cert))
+            .map(|cert| cert.as_slice())
+            .unwrap_or(anchor);
+
+        if signer.signature_alg == asn1::OID_ED25519 {
+            // mbedtls can not parse Ed25519 certificates, so only a
+            // directly pinned signer is possible
+            if signer_cert != anchor {
+                return Ok(false);
+            }
+            let parts = asn1::parse_certificate(signer_cert)?;
+            let public = ed25519::public_key_from_spki(parts.spki.raw).ok_or(CmsError::BadKey)?;
+            return Ok(ed25519::verify(&public, &tbs, &signer.signature));
+        }
+
+        if signer_cert != anchor && !self.chains_to(signer_cert, anchor)? {
+            return Ok(false);
+        }
+
+        // RSASSA-PSS is taken to be PS256, other parameters fail to verify
+        let md_oid = match signer.signature_alg.as_slice() {
+            asn1::OID_ECDSA_SHA256 | asn1::OID_RSA_SHA256 | asn1::OID_RSASSA_PSS => asn1::OID_SHA256,
+            asn1::OID_ECDSA_SHA384 => asn1::OID_SHA384,
+            asn1::OID_ECDSA_SHA512 => asn1::OID_SHA512,
+            asn1::OID_RSA_ENCRYPTION => signer.digest_alg.as_slice(),
+            _ => return Err(CmsError::UnsupportedAlgorithm),
+        };
+        let (hash, md_type) = digest(md_oid, &tbs)?;
+
+        let mut cert = Certificate::from_der(signer_cert)?;
+        if signer.signature_alg == asn1::OID_RSASSA_PSS {
+            use_pss(cert.public_key_mut());
+        }
+        Ok(cert.public_key_mut().verify(md_type, &hash, &signer.signature).is_ok())
+    }
+
+    fn chains_to(&self, signer_cert: &[u8], anchor: &[u8]) -> Result<bool, CmsError> {
+        let mut chain = vec![signer_cert.to_vec()];
+        chain.extend(self.certificates.iter().filter(|der| der.as_slice() != signer_cert).cloned());
+        Ok(voucher_validation::chain_verifies(&chain, anchor)?)
+    }
+}
+
+fn issuer_and_serial(cert: &[u8]) -> Result<Vec<u8>, Asn1Error> {
+    let parts = asn1::parse_certificate(cert)?;
+    Ok(der_constructed(asn1::TAG_SEQUENCE, &[parts.issuer.raw, parts.serial.raw]))
+}
+
+fn identifies(sid: &[u8], cert: &[u8]) -> bool {
+    match issuer_and_serial(cert) {
+        Ok(ias) => ias == sid,
+        Err(_) => false,
+    }
+}
+
+/*
+ * EnvelopedData, bare or in its ContentInfo, with the content key
+ * transported to our RSA key (KeyTransRecipientInfo) and the content in
+ * AES-CBC.  Returns the decrypted content.
+ */
+pub fn decrypt_enveloped(der: &[u8], recipient_cert: &[u8], key_pem: &[u8]) -> Result<Vec<u8>, CmsError> {
+    let outer = DerReader::new(der).expect(asn1::TAG_SEQUENCE)?;
+    let enveloped = match outer.reader().peek_tag() {
+        Some(asn1::TAG_OID) => {
+            let mut content_info = outer.reader();
+            if content_info.expect(asn1::TAG_OID)?.value != OID_ENVELOPED_DATA {
+                return Err(CmsError::NotEnvelopedData);
+            }
+            content_info.expect(asn1::tag_context(0))?.reader().expect(asn1::TAG_SEQUENCE)?
+        },
+        _ => outer,
+    };
+
+    let mut fields = enveloped.reader();
+    fields.expect(asn1::TAG_INTEGER)?;                     // version
+    fields.read_optional(asn1::tag_context(0))?;          // originatorInfo
+    let recipients = fields.expect(asn1::TAG_SET)?;
+    let mut encrypted = fields.expect(asn1::TAG_SEQUENCE)?.reader();
+    encrypted.expect(asn1::TAG_OID)?;                      // contentType
+    let content_alg = encrypted.expect(asn1::TAG_SEQUENCE)?;
+    let content = encrypted.expect(0x80)?.value;          // [0] IMPLICIT OCTET STRING
+
+    // only KeyTransRecipientInfo, which needs an RSA key; an EC key
+    // would be sent a KeyAgreeRecipientInfo (ECDH), which is not done here
+    let mut pk = Pk::from_private_key(&utils::null_terminate_bytes!(key_pem), None)?;
+    match pk.pk_type() {
+        mbedtls::pk::Type::Rsa => {},
+        mbedtls::pk::Type::Eckey | mbedtls::pk::Type::Ecdsa => return Err(CmsError::KeyAgreeUnsupported),
+        _ => return Err(CmsError::UnsupportedAlgorithm),
+    }
+    let our_rid = issuer_and_serial(recipient_cert)?;
+
+    let mut content_key = None;
+    let mut key_agree = false;
+    let mut infos = recipients.reader();
+    while !infos.is_empty() {
+        let info = infos.read()?;
+        if info.tag != asn1::TAG_SEQUENCE {
+            key_agree |= info.tag == asn1::tag_context(1);
+            continue;                                      // not key transport
+        }
+        let mut ktri = info.reader();
+        ktri.expect(asn1::TAG_INTEGER)?;
+        let rid = ktri.read()?;
+        if rid.tag == asn1::TAG_SEQUENCE && rid.raw != our_rid.as_slice() {
+            continue;
+        }
+        if algorithm_oid(&ktri.expect(asn1::TAG_SEQUENCE)?)? != asn1::OID_RSA_ENCRYPTION {
+            continue;
+        }
+        let encrypted_key = ktri.expect(asn1::TAG_OCTET_STRING)?.value;
+        let mut key = vec![0u8; (pk.len() + 7) / 8];
+        if let Ok(len) = pk.decrypt(encrypted_key, &mut key, &mut support_rand::signing_rng()?) {
+            key.truncate(len);
+            content_key = Some(key);
+            break;
+        }
+    }
+    let content_key = match content_key {
+        Some(key) => key,
+        None if key_agree => return Err(CmsError::KeyAgreeUnsupported),
+        None => return Err(CmsError::NoRecipient),
+    };
+
+    let mut alg = content_alg.reader();
+    let bits = match alg.expect(asn1::TAG_OID)?.value {
+        OID_AES128_CBC => 128,
+        OID_AES256_CBC => 256,
+        _ => return Err(CmsError::UnsupportedAlgorithm),
+    };
+    let iv = alg.expect(asn1::TAG_OCTET_STRING)?.value;
+    if content_key.len() * 8 != bits {
+        return Err(CmsError::BadKey);
+    }
+
+    let cipher = Cipher::<Decryption, Traditional, _>::new(CipherId::Aes, CipherMode::CBC, bits as u32)?
+        .set_key_iv(&content_key, iv)?;
+    let mut plain = vec![0u8; content.len() + 16];
+    let (len, _) = cipher.decrypt(content, &mut plain)?;
+    plain.truncate(len);
+    Ok(plain)
+}
+
+/// digest and signature algorithm OIDs for a voucher signing algorithm
+fn cms_algorithms(alg: VoucherAlgorithm) -> (&'static [u8], &'static [u8]) {
+    match alg {
+        VoucherAlgorithm::ES256 => (asn1::OID_SHA256, asn1::OID_ECDSA_SHA256),
+        VoucherAlgorithm::ES384 => (asn1::OID_SHA384, asn1::OID_ECDSA_SHA384),
+        VoucherAlgorithm::ES512 => (asn1::OID_SHA512, asn1::OID_ECDSA_SHA512),
+        VoucherAlgorithm::PS256 => (asn1::OID_SHA256, asn1::OID_RSASSA_PSS),
+        // RFC8419 section 3.1
+        VoucherAlgorithm::EdDSA => (asn1::OID_SHA512, asn1::OID_ED25519),
+    }
+}
+
+/*
+ * RFC4055 section 3.1: PS256 is RSASSA-PSS with SHA-256, MGF1 with
+ * SHA-256, and a 32 byte salt, which is what mbedtls makes
+ */
+fn rsassa_pss_sha256() -> Vec<u8> {
+    let sha256 = algorithm_id(asn1::OID_SHA256, true);
+    let params = der_constructed(asn1::TAG_SEQUENCE, &[
+        &der_constructed(asn1::tag_context(0), &[&sha256]),
+        &der_constructed(asn1::tag_context(1), &[&der_constructed(asn1::TAG_SEQUENCE, &[
+            &der_tlv(asn1::TAG_OID, asn1::OID_MGF1),
+            &sha256,
+        ])]),
+        &der_constructed(asn1::tag_context(2), &[&der_tlv(asn1::TAG_INTEGER, &[32])]),
+    ]);
+    der_constructed(asn1::TAG_SEQUENCE, &[&der_tlv(asn1::TAG_OID, asn1::OID_RSASSA_PSS), &params])
+}
+
+fn attribute(oid: &[u8], value: &[u8]) -> Vec<u8> {
+    der_constructed(asn1::TAG_SEQUENCE, &[
+        &der_tlv(asn1::TAG_OID, oid),
+        &der_tlv(asn1::TAG_SET, value),
+    ])
+}
+
+fn sign_bytes(key_pem: &[u8], alg: VoucherAlgorithm, sig_oid: &[u8], tbs: &[u8]) -> Result<Vec<u8>, CmsError> {
+    if alg == VoucherAlgorithm::EdDSA {
+        let seed = utils::pem_to_der(key_pem)
+            .and_then(|der| ed25519::private_key_from_pkcs8(&der))
+            .ok_or(CmsError::BadKey)?;
+        return ed25519::sign(&seed, tbs).ok_or(CmsError::BadKey);
+    }
+
+    let md_oid = match sig_oid {
+        asn1::OID_ECDSA_SHA384 => asn1::OID_SHA384,
+        asn1::OID_ECDSA_SHA512 => asn1::OID_SHA512,
+        _ => asn1::OID_SHA256,
+    };
+    let (hash, md_type) = digest(md_oid, tbs)?;
+    let mut pk = Pk::from_private_key(&utils::null_terminate_bytes!(key_pem), None)?;
+
+    let mut sig = vec![0u8; std::cmp::max(ECDSA_MAX_LEN, (pk.len() + 7) / 8)];
+    let sig_len = if alg == VoucherAlgorithm::PS256 {
+        if pk.pk_type() != mbedtls::pk::Type::Rsa {
+            return Err(CmsError::BadKey);
+        }
+        use_pss(&mut pk);
+        pk.sign(md_type, &hash, &mut sig, &mut support_rand::signing_rng()?)?
+    } else {
+        // CMS keeps the DER form that mbedtls produces
+        pk.sign_deterministic(md_type, &hash, &mut sig, &mut support_rand::signing_rng()?)?
+    };
+    sig.truncate(sig_len);
+    Ok(sig)
+}
+
+/// a ContentInfo with SignedData over `content`, by the holder of `cert_der`
+pub fn sign(content_type: &[u8],
+            content:      &[u8],
+            cert_der:     &[u8],
+            key_pem:      &[u8],
+            alg:          VoucherAlgorithm) -> Result<Vec<u8>, CmsError> {
+    let (md_oid, sig_oid) = cms_algorithms(alg);
+    let (message_digest, _) = digest(md_oid, content)?;
+
+    // DER wants SET OF sorted by encoding
+    let mut attrs = vec![
+        attribute(OID_CONTENT_TYPE,   &der_tlv(asn1::TAG_OID, content_type)),
+        attribute(OID_MESSAGE_DIGEST, &der_tlv(asn1::TAG_OCTET_STRING, &message_digest)),
+    ];
+    attrs.sort();
+    let attrs: Vec<&[u8]> = attrs.iter().map(|a| a.as_slice()).collect();
+    let mut signed_attrs = der_constructed(asn1::TAG_SET, &attrs);
+
+    let signature = sign_bytes(key_pem, alg, sig_oid, &signed_attrs)?;
+    signed_attrs[0] = asn1::tag_context(0);
+
+    let digest_alg = algorithm_id(md_oid, false);
+    let signature_alg = match sig_oid {
+        asn1::OID_RSASSA_PSS => rsassa_pss_sha256(),
+        _ => algorithm_id(sig_oid, false),
+    };
+    let signer_info = der_constructed(asn1::TAG_SEQUENCE, &[
+        &der_tlv(asn1::TAG_INTEGER, &[1]),
+        &issuer_and_serial(cert_der)?,
+        &digest_alg,
+        &signed_attrs,
+        &signature_alg,
+        &der_tlv(asn1::TAG_OCTET_STRING, &signature),
+    ]);
+
+    let signed_data = der_constructed(asn1::TAG_SEQUENCE, &[
+        &der_tlv(asn1::TAG_INTEGER, &[3]),
+        &der_tlv(asn1::TAG_SET, &digest_alg),
+        &der_constructed(asn1::TAG_SEQUENCE, &[
+            &der_tlv(asn1::TAG_OID, content_type),
+            &der_constructed(asn1::tag_context(0), &[&der_tlv(asn1::TAG_OCTET_STRING, content)]),
+        ]),
+        &der_tlv(asn1::tag_context(0), cert_der),
+        &der_tlv(asn1::TAG_SET, &signer_info),
+    ]);
+
+    Ok(der_constructed(asn1::TAG_SEQUENCE, &[
+        &der_tlv(asn1::TAG_OID, OID_SIGNED_DATA),
+        &der_constructed(asn1::tag_context(0), &[&signed_data]),
+    ]))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::test_fixtures::{ed25519_cert, ed25519_key_pem};
+
+    #[test]
+    fn sign_and_verify() {
+        let cert = ed25519_cert();
+        let content = br#"{"ietf-voucher-request:voucher":{"assertion":"proximity"}}"#;
+        let der = sign(OID_ANIMA_JSON_VOUCHER, content, &cert, &ed25519_key_pem(),
+                       VoucherAlgorithm::EdDSA).unwrap();
+
+        let signed = SignedData::decode(&der).unwrap();
+        assert_eq!(signed.content_type, OID_ANIMA_JSON_VOUCHER);
+        assert_eq!(signed.content, content.to_vec());
+        assert_eq!(signed.certificates, vec![cert.clone()]);
+        assert_eq!(signed.signers.len(), 1);
+        assert_eq!(signed.signers[0].signature_alg, asn1::OID_ED25519);
+        assert!(signed.verify(&cert));
+
+        let pem = utils::der_to_pem("CERTIFICATE", &cert);
+        assert!(signed.verify(pem.as_bytes()));
+    }
+
+    #[test]
+    fn pss_algorithm_identifier() {
+        // the RFC4055 section 3.1 RSASSA-PSS AlgorithmIdentifier for SHA-256
+        let expected: &[u8] = &[
+            0x30, 0x41, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0a,
+            0x30, 0x34,
+              0xa0, 0x0f, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00,
+              0xa1, 0x1c, 0x30, 0x1a, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x08,
+                          0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00,
+              0xa2, 0x03, 0x02, 0x01, 0x20,
+        ];
+        assert_eq!(rsassa_pss_sha256(), expected);
+        assert_eq!(cms_algorithms(VoucherAlgorithm::PS256).1, asn1::OID_RSASSA_PSS);
+    }
+
+    #[test]
+    fn tampered_content() {
+        let cert = ed25519_cert();
+        let der = sign(OID_ANIMA_JSON_VOUCHER, b"{}", &cert, &ed25519_key_pem(),
+                       VoucherAlgorithm::EdDSA).unwrap();
+
+        let mut signed = SignedData::decode(&der).unwrap();
+        signed.content = b"[]".to_vec();
+        assert!(!signed.verify(&cert));
+
+        let mut signed = SignedData::decode(&der).unwrap();
+        signed.content_type = asn1::OID_SHA256.to_vec();
+        assert!(!signed.verify(&cert));
+    }
+
+    #[test]
+    fn not_signed_data() {
+        let data = der_constructed(asn1::TAG_SEQUENCE, &[&der_tlv(asn1::TAG_OID, asn1::OID_SHA256)]);
+        assert!(matches!(SignedData::decode(&data), Err(CmsError::NotSignedData)));
+        assert!(matches!(SignedData::decode(b"{}"), Err(CmsError::Malformed(_))));
+    }
+}
+/*
+ * Local Variables:
+ * mode: rust
+ * compile-command: "cd .. && cargo build"
+ * End:
+ */
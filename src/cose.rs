@@ -0,0 +1,182 @@
+/*
+ * Copyright [2021] <mcr@sandelman.ca>
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+ *
+ */
+
+// COSE_Sign1 (RFC8152 section 4.2), for what minerva_voucher can not
+// express itself, such as EdDSA.
+
+use crate::cbor::{self, CborError, CborValue};
+use crate::custom_voucher::VoucherAlgorithm;
+
+pub const ALG_ES256: i64 = -7;
+pub const ALG_EDDSA: i64 = -8;
+pub const ALG_ES384: i64 = -35;
+pub const ALG_ES512: i64 = -36;
+pub const ALG_PS256: i64 = -37;
+
+const HEADER_ALG: i64 = 1;
+const TAG_SIGN1:  u64 = 18;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Sign1 {
+    pub protected:   Vec<u8>,
+    pub unprotected: CborValue,
+    pub payload:     Vec<u8>,
+    pub signature:   Vec<u8>,
+    pub tagged:      bool,
+}
+
+impl Sign1 {
+    pub fn decode(raw: &[u8]) -> Result<Sign1, CborError> {
+        let (value, tagged) = match cbor::decode(raw)? {
+            CborValue::Tag(TAG_SIGN1, inner) => (*inner, true),
+            value => (value, false),
+        };
+
+        let mut items = match value {
+            CborValue::Array(items) if items.len() == 4 => items.into_iter(),
+            _ => return Err(CborError::Unsupported),
+        };
+        let bytes = |item: Option<CborValue>| match item {
+            Some(CborValue::Bytes(b)) => Ok(b),
+            _ => Err(CborError::Unsupported),
+        };
+
+        let protected   = bytes(items.next())?;
+        let unprotected = items.next().unwrap();
+        let payload     = bytes(items.next())?;
+        let signature   = bytes(items.next())?;
+
+        Ok(Sign1 {
+            protected:   protected,
+            unprotected: unprotected,
+            payload:     payload,
+            signature:   signature,
+            tagged:      tagged,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let sign1 = CborValue::Array(vec![
+            CborValue::Bytes(self.protected.clone()),
+            self.unprotected.clone(),
+            CborValue::Bytes(self.payload.clone()),
+            CborValue::Bytes(self.signature.clone()),
+        ]);
+        if self.tagged {
+            cbor::encode(&CborValue::Tag(TAG_SIGN1, Box::new(sign1)))
+        } else {
+            cbor::encode(&sign1)
+        }
+    }
+
+    pub fn alg(&self) -> Option<i64> {
+        if self.protected.is_empty() {
+            return None;
+        }
+        cbor::decode(&self.protected).ok()?
+            .map_get(HEADER_ALG)?
+            .as_integer()
+    }
+
+    /// the same structure, with the algorithm in the protected header replaced
+    pub fn with_alg(&self, alg: i64) -> Sign1 {
+        let mut entries = match cbor::decode(&self.protected) {
+            Ok(CborValue::Map(entries)) => entries,
+            _ => Vec::new(),
+        };
+        entries.retain(|(k, _)| *k != CborValue::Integer(HEADER_ALG));
+        entries.insert(0, (CborValue::Integer(HEADER_ALG), CborValue::Integer(alg)));
+
+        Sign1 {
+            protected: cbor::encode(&CborValue::Map(entries)),
+            ..self.clone()
+        }
+    }
+
+    pub fn to_be_signed(&self) -> Vec<u8> {
+        sig_structure(&self.protected, &self.payload)
+    }
+}
+
+pub fn alg_id(alg: VoucherAlgorithm) -> i64 {
+    match alg {
+        VoucherAlgorithm::ES256 => ALG_ES256,
+        VoucherAlgorithm::ES384 => ALG_ES384,
+        VoucherAlgorithm::ES512 => ALG_ES512,
+        VoucherAlgorithm::PS256 => ALG_PS256,
+        VoucherAlgorithm::EdDSA => ALG_EDDSA,
+    }
+}
+
+pub fn protected_header(alg: i64) -> Vec<u8> {
+    cbor::encode(&CborValue::Map(vec![(CborValue::Integer(HEADER_ALG), CborValue::Integer(alg))]))
+}
+
+/// Sig_structure for COSE_Sign1, with empty external_aad
+pub fn sig_structure(protected: &[u8], payload: &[u8]) -> Vec<u8> {
+    cbor::encode(&CborValue::Array(vec![
+        CborValue::Text("Signature1".to_string()),
+        CborValue::Bytes(protected.to_vec()),
+        CborValue::Bytes(Vec::new()),
+        CborValue::Bytes(payload.to_vec()),
+    ]))
+}
+
+pub fn payload_from_sig_structure(raw: &[u8]) -> Option<Vec<u8>> {
+    match cbor::decode(raw).ok()? {
+        CborValue::Array(items) if items.len() == 4 => items[3].as_bytes().map(|b| b.to_vec()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn replace_alg() {
+        let sign1 = Sign1 {
+            protected:   protected_header(ALG_EDDSA),
+            unprotected: CborValue::Map(vec![]),
+            payload:     vec![0xa1, 0x01, 0x02],
+            signature:   vec![0u8; 64],
+            tagged:      true,
+        };
+        assert_eq!(sign1.protected, vec![0xa1, 0x01, 0x27]);
+
+        let decoded = Sign1::decode(&sign1.encode()).unwrap();
+        assert_eq!(decoded, sign1);
+        assert_eq!(decoded.alg(), Some(ALG_EDDSA));
+
+        let placeholder = decoded.with_alg(ALG_ES256);
+        assert_eq!(placeholder.protected, vec![0xa1, 0x01, 0x26]);
+        assert_eq!(placeholder.payload, sign1.payload);
+    }
+
+    #[test]
+    fn sig_structure_payload() {
+        let tbs = sig_structure(&protected_header(ALG_ES256), b"voucher");
+        assert_eq!(payload_from_sig_structure(&tbs), Some(b"voucher".to_vec()));
+    }
+}
+
+/*
+ * Local Variables:
+ * mode: rust
+ * compile-command: "cd .. && cargo build"
+ * End:
+ */
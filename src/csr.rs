@@ -0,0 +1,443 @@
+/*
+ * Copyright [2021] <mcr@sandelman.ca>
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+ *
+ */
+
+/*
+ * What the registrar asks for in EST /csrattrs (RFC7030 section 4.5,
+ * RFC8994 section 6.2.5.1), and the PKCS#10 (RFC2986) request for a new
+ * LDevID key that honours it.
+ */
+
+use std::fmt;
+
+use mbedtls::hash as mbedtls_hash;
+use mbedtls::pk::{EcGroupId, Pk, Type as PkType, ECDSA_MAX_LEN};
+
+use crate::asn1::{self, der_constructed, der_tlv, Asn1Error, DerReader};
+use crate::support_rand;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KeyType {
+    Ec(EcGroupId),
+    Rsa(u32),
+}
+
+pub const DEFAULT_KEY_TYPE: KeyType = KeyType::Ec(EcGroupId::SecP256R1);
+const DEFAULT_RSA_BITS: u32 = 2048;
+const RSA_EXPONENT:     u32 = 65537;
+
+#[derive(Debug)]
+pub enum CsrError {
+    Malformed(Asn1Error),
+    UnsupportedKey,
+    CryptoFailed(mbedtls::Error),
+}
+
+impl fmt::Display for CsrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CsrError::Malformed(error) => {
+                write!(f, "CSR attributes malformed: {}", error)
+            },
+            CsrError::UnsupportedKey => {
+                write!(f, "CSR attributes ask for a key type that is not supported")
+            },
+            CsrError::CryptoFailed(error) => {
+                write!(f, "CSR key or signature failed {}", error)
+            }
+        }
+    }
+}
+
+impl From<Asn1Error> for CsrError {
+    fn from(error: Asn1Error) -> Self {
+        CsrError::Malformed(error)
+    }
+}
+
+impl From<mbedtls::Error> for CsrError {
+    fn from(error: mbedtls::Error) -> Self {
+        CsrError::CryptoFailed(error)
+    }
+}
+
+fn curve_from_oid(oid: &[u8]) -> Option<EcGroupId> {
+    match oid {
+        asn1::OID_SECP256R1 => Some(EcGroupId::SecP256R1),
+        asn1::OID_SECP384R1 => Some(EcGroupId::SecP384R1),
+        asn1::OID_SECP521R1 => Some(EcGroupId::SecP521R1),
+        _ => None,
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct CsrAttributes {
+    pub key_type:           Option<KeyType>,
+    /// signature algorithm OID to sign the CSR with
+    pub signature_alg:      Option<Vec<u8>>,
+    pub challenge_password: bool,
+    /// each a complete DER Extension, for the extensionRequest
+    pub extensions:         Vec<Vec<u8>>,
+    /// the registrar wants to generate the key (RFC7030 section 4.4)
+    pub server_keygen:      bool,
+}
+
+impl CsrAttributes {
+    /// CsrAttrs ::= SEQUENCE OF AttrOrOID; an empty body asks for nothing
+    pub fn decode(der: &[u8]) -> Result<CsrAttributes, CsrError> {
+        let mut attrs = CsrAttributes::default();
+        if der.is_empty() {
+            return Ok(attrs);
+        }
+
+        let mut items = DerReader::new(der).expect(asn1::TAG_SEQUENCE)?.reader();
+        while !items.is_empty() {
+            let item = items.read()?;
+            match item.tag {
+                asn1::TAG_OID => attrs.oid(item.value),
+                asn1::TAG_SEQUENCE => {
+                    let mut attr = item.reader();
+                    let attr_type = attr.expect(asn1::TAG_OID)?;
+                    let mut values = attr.expect(asn1::TAG_SET)?.reader();
+                    while !values.is_empty() {
+                        attrs.attribute(attr_type.value, values.read()?)?;
+                    }
+                },
+                found => return Err(CsrError::Malformed(Asn1Error::UnexpectedTag {
+                    expected: asn1::TAG_SEQUENCE, found: found
+                })),
+            }
+        }
+        Ok(attrs)
+    }
+
+    /// a bare OID: something to include, or an algorithm to use
+    fn oid(&mut self, oid: &[u8]) {
+        match oid {
+            asn1::OID_CHALLENGE_PASSWORD => self.challenge_password = true,
+            asn1::OID_DECRYPT_KEY_ID | asn1::OID_ASYMM_DECRYPT_KEY_ID => self.server_keygen = true,
+            asn1::OID_ECDSA_SHA256 | asn1::OID_ECDSA_SHA384 | asn1::OID_ECDSA_SHA512
+                | asn1::OID_RSA_SHA256 => self.signature_alg = Some(oid.to_vec()),
+            asn1::OID_EC_PUBLIC_KEY if self.key_type.is_none() => self.key_type = Some(DEFAULT_KEY_TYPE),
+            asn1::OID_RSA_ENCRYPTION if self.key_type.is_none() => self.key_type = Some(KeyType::Rsa(DEFAULT_RSA_BITS)),
+            _ => {},
+        }
+    }
+
+    fn attribute(&mut self, attr_type: &[u8], value: asn1::Tlv) -> Result<(), CsrError> {
+        match attr_type {
+            asn1::OID_EC_PUBLIC_KEY => {
+                if value.tag != asn1::TAG_OID {
+                    return Err(CsrError::UnsupportedKey);
+                }
+                let curve = curve_from_oid(value.value).ok_or(CsrError::UnsupportedKey)?;
+                self.key_type = Some(KeyType::Ec(curve));
+            },
+            asn1::OID_RSA_ENCRYPTION => {
+                if value.tag != asn1::TAG_INTEGER || value.value.len() > 4 {
+                    return Err(CsrError::UnsupportedKey);
+                }
+                let bits = value.value.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32);
+                self.key_type = Some(KeyType::Rsa(bits));
+            },
+            asn1::OID_CHALLENGE_PASSWORD => self.challenge_password = true,
+            asn1::OID_DECRYPT_KEY_ID | asn1::OID_ASYMM_DECRYPT_KEY_ID => self.server_keygen = true,
+            asn1::OID_EXTENSION_REQUEST => {
+                // Extensions to copy; a bare OID asks for a value we can not know
+                if value.tag == asn1::TAG_SEQUENCE {
+                    let mut extensions = value.reader();
+                    while !extensions.is_empty() {
+                        self.extensions.push(extensions.expect(asn1::TAG_SEQUENCE)?.raw.to_vec());
+                    }
+                }
+            },
+            asn1::OID_SUBJECT_ALT_NAME => {
+                // the GeneralNames to put into the subjectAltName
+                let names = DerReader::new(value.raw).expect(asn1::TAG_SEQUENCE)?;
+                self.extensions.push(der_constructed(asn1::TAG_SEQUENCE, &[
+                    &der_tlv(asn1::TAG_OID, asn1::OID_SUBJECT_ALT_NAME),
+                    &der_tlv(asn1::TAG_OCTET_STRING, names.raw),
+                ]));
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// the RFC8994 AcpNodeName otherName of a requested subjectAltName
+    pub fn acp_node_name(&self) -> Option<String> {
+        self.extensions.iter().find_map(|ext| acp_node_name(ext).ok().flatten())
+    }
+}
+
+fn acp_node_name(extension: &[u8]) -> Result<Option<String>, Asn1Error> {
+    let mut fields = DerReader::new(extension).expect(asn1::TAG_SEQUENCE)?.reader();
+    if fields.expect(asn1::TAG_OID)?.value != asn1::OID_SUBJECT_ALT_NAME {
+        return Ok(None);
+    }
+    fields.read_optional(0x01)?;                     // critical
+    let value = fields.expect(asn1::TAG_OCTET_STRING)?;
+
+    let mut names = DerReader::new(value.value).expect(asn1::TAG_SEQUENCE)?.reader();
+    while !names.is_empty() {
+        let name = names.read()?;
+        if name.tag != asn1::tag_context(0) {       // otherName
+            continue;
+        }
+        let mut other = name.reader();
+        if other.expect(asn1::TAG_OID)?.value != asn1::OID_ACP_NODE_NAME {
+            continue;
+        }
+        let text = other.expect(asn1::tag_context(0))?.reader().read()?;
+        return Ok(Some(String::from_utf8_lossy(text.value).into_owned()));
+    }
+    Ok(None)
+}
+
+pub fn generate_key(key_type: KeyType) -> Result<Pk, mbedtls::Error> {
+    let mut rng = support_rand::signing_rng()?;
+    match key_type {
+        KeyType::Ec(curve) => Pk::generate_ec(&mut rng, curve),
+        KeyType::Rsa(bits) => Pk::generate_rsa(&mut rng, bits, RSA_EXPONENT),
+    }
+}
+
+/// signature and digest algorithm for the key: the one asked for if it
+/// fits the key, otherwise the usual one for the key
+fn signature_algorithm(pk: &Pk, requested: Option<&[u8]>) -> (&'static [u8], &'static [u8]) {
+    if pk.pk_type() == PkType::Rsa {
+        return (asn1::OID_RSA_SHA256, asn1::OID_SHA256);
+    }
+    match (requested, pk.curve()) {
+        (Some(asn1::OID_ECDSA_SHA256), _) => (asn1::OID_ECDSA_SHA256, asn1::OID_SHA256),
+        (Some(asn1::OID_ECDSA_SHA384), _) => (asn1::OID_ECDSA_SHA384, asn1::OID_SHA384),
+        (Some(asn1::OID_ECDSA_SHA512), _) => (asn1::OID_ECDSA_SHA512, asn1::OID_SHA512),
+        (_, Ok(EcGroupId::SecP384R1))     => (asn1::OID_ECDSA_SHA384, asn1::OID_SHA384),
+        (_, Ok(EcGroupId::SecP521R1))     => (asn1::OID_ECDSA_SHA512, asn1::OID_SHA512),
+        _ => (asn1::OID_ECDSA_SHA256, asn1::OID_SHA256),
+    }
+}
+
+fn md_type(oid: &[u8]) -> (mbedtls_hash::Type, usize) {
+    match oid {
+        asn1::OID_SHA384 => (mbedtls_hash::Type::Sha384, 48),
+        asn1::OID_SHA512 => (mbedtls_hash::Type::Sha512, 64),
+        _ => (mbedtls_hash::Type::Sha256, 32),
+    }
+}
+
+fn is_printable(text: &[u8]) -> bool {
+    text.iter().all(|c| c.is_ascii_alphanumeric() || b" '()+,-./:=?".contains(c))
+}
+
+/// Name with just the X520SerialNumber, as the IDevID had it
+fn subject_name(serial_number: &[u8]) -> Vec<u8> {
+    let tag = if is_printable(serial_number) { asn1::TAG_PRINTABLE_STRING } else { asn1::TAG_UTF8_STRING };
+    let atv = der_constructed(asn1::TAG_SEQUENCE, &[
+        &der_tlv(asn1::TAG_OID, asn1::OID_SERIAL_NUMBER),
+        &der_tlv(tag, serial_number),
+    ]);
+    der_constructed(asn1::TAG_SEQUENCE, &[&der_tlv(asn1::TAG_SET, &atv)])
+}
+
+/// the same kind of key as `pk`, for renewals
+pub fn key_type_of(pk: &Pk) -> Option<KeyType> {
+    match pk.pk_type() {
+        PkType::Rsa => Some(KeyType::Rsa(pk.len() as u32)),
+        PkType::Eckey | PkType::Ecdsa => pk.curve().ok().map(KeyType::Ec),
+        _ => None,
+    }
+}
+
+/// a CertificationRequest for `pk`, signed by it
+pub fn build_csr(pk:                 &mut Pk,
+                 serial_number:      &[u8],
+                 attrs:              &CsrAttributes,
+                 challenge_password: Option<&str>) -> Result<Vec<u8>, CsrError> {
+    build_csr_for(pk, &subject_name(serial_number), attrs, challenge_password)
+}
+
+/// as build_csr(), with the subject Name given.  RFC7030 section 4.2.2 wants
+/// a re-enroll to repeat the subject of the current certificate.
+pub fn build_csr_for(pk:                 &mut Pk,
+                     subject:            &[u8],
+                     attrs:              &CsrAttributes,
+                     challenge_password: Option<&str>) -> Result<Vec<u8>, CsrError> {
+    let mut attributes = Vec::new();
+    if attrs.challenge_password {
+        match challenge_password {
+            Some(password) => attributes.push(der_constructed(asn1::TAG_SEQUENCE, &[
+                &der_tlv(asn1::TAG_OID, asn1::OID_CHALLENGE_PASSWORD),
+                &der_constructed(asn1::TAG_SET, &[&der_tlv(asn1::TAG_UTF8_STRING, password.as_bytes())]),
+            ])),
+            None => println!("registrar asks for a challengePassword, but none is configured"),
+        }
+    }
+    if !attrs.extensions.is_empty() {
+        let extensions: Vec<&[u8]> = attrs.extensions.iter().map(|e| e.as_slice()).collect();
+        attributes.push(der_constructed(asn1::TAG_SEQUENCE, &[
+            &der_tlv(asn1::TAG_OID, asn1::OID_EXTENSION_REQUEST),
+            &der_constructed(asn1::TAG_SET, &[&der_constructed(asn1::TAG_SEQUENCE, &extensions)]),
+        ]));
+    }
+    // DER wants SET OF sorted by encoding
+    attributes.sort();
+    let attributes: Vec<&[u8]> = attributes.iter().map(|a| a.as_slice()).collect();
+
+    let info = der_constructed(asn1::TAG_SEQUENCE, &[
+        &der_tlv(asn1::TAG_INTEGER, &[0]),
+        subject,
+        &pk.write_public_der_vec()?,
+        &der_constructed(asn1::tag_context(0), &attributes),
+    ]);
+
+    let (sig_oid, md_oid) = signature_algorithm(pk, attrs.signature_alg.as_deref());
+    let (md, md_len) = md_type(md_oid);
+    let mut hash = vec![0u8; md_len];
+    mbedtls_hash::Md::hash(md, &info, &mut hash)?;
+
+    let mut sig = vec![0u8; std::cmp::max(ECDSA_MAX_LEN, (pk.len() + 7) / 8)];
+    let sig_len = pk.sign(md, &hash, &mut sig, &mut support_rand::signing_rng()?)?;
+    sig.truncate(sig_len);
+
+    let mut bits = vec![0u8];
+    bits.extend_from_slice(&sig);
+
+    let sig_alg = if sig_oid == asn1::OID_RSA_SHA256 {
+        der_constructed(asn1::TAG_SEQUENCE, &[&der_tlv(asn1::TAG_OID, sig_oid), &der_tlv(asn1::TAG_NULL, &[])])
+    } else {
+        der_constructed(asn1::TAG_SEQUENCE, &[&der_tlv(asn1::TAG_OID, sig_oid)])
+    };
+
+    Ok(der_constructed(asn1::TAG_SEQUENCE, &[
+        &info,
+        &sig_alg,
+        &der_tlv(asn1::TAG_BIT_STRING, &bits),
+    ]))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // RFC7030 section 4.5.2
+    static RFC7030_CSRATTRS: &str =
+        "MEEGCSqGSIb3DQEJBzASBgcqhkjOPQIBMQcGBSuBBAAiMBYGCSqGSIb3DQEJDjEJBgcrBgEBAQEWBggqhkjOPQQDAw==";
+
+    static ACP_NODE_NAME: &str = "rfcSELF+fd89b714f3db00000200000064000000+area51.research@acp.example.com";
+
+    fn acp_san() -> Vec<u8> {
+        der_constructed(asn1::TAG_SEQUENCE, &[
+            &der_constructed(asn1::tag_context(0), &[
+                &der_tlv(asn1::TAG_OID, asn1::OID_ACP_NODE_NAME),
+                &der_constructed(asn1::tag_context(0), &[&der_tlv(asn1::TAG_IA5_STRING, ACP_NODE_NAME.as_bytes())]),
+            ]),
+        ])
+    }
+
+    #[test]
+    fn rfc7030_example() {
+        let attrs = CsrAttributes::decode(&base64::decode(RFC7030_CSRATTRS).unwrap()).unwrap();
+        assert_eq!(attrs.key_type, Some(KeyType::Ec(EcGroupId::SecP384R1)));
+        assert_eq!(attrs.signature_alg.as_deref(), Some(asn1::OID_ECDSA_SHA384));
+        assert!(attrs.challenge_password);
+        assert!(attrs.extensions.is_empty());
+
+        assert_eq!(CsrAttributes::decode(&[]).unwrap(), CsrAttributes::default());
+    }
+
+    #[test]
+    fn acp_node_name_requested() {
+        // RFC8994 style: an extensionRequest carrying the subjectAltName
+        let extension = der_constructed(asn1::TAG_SEQUENCE, &[
+            &der_tlv(asn1::TAG_OID, asn1::OID_SUBJECT_ALT_NAME),
+            &der_tlv(asn1::TAG_OCTET_STRING, &acp_san()),
+        ]);
+        let csrattrs = der_constructed(asn1::TAG_SEQUENCE, &[
+            &der_constructed(asn1::TAG_SEQUENCE, &[
+                &der_tlv(asn1::TAG_OID, asn1::OID_EXTENSION_REQUEST),
+                &der_constructed(asn1::TAG_SET, &[&der_constructed(asn1::TAG_SEQUENCE, &[&extension])]),
+            ]),
+        ]);
+        let attrs = CsrAttributes::decode(&csrattrs).unwrap();
+        assert_eq!(attrs.extensions, vec![extension.clone()]);
+        assert_eq!(attrs.acp_node_name().as_deref(), Some(ACP_NODE_NAME));
+
+        // or the subjectAltName attribute with the GeneralNames directly
+        let csrattrs = der_constructed(asn1::TAG_SEQUENCE, &[
+            &der_constructed(asn1::TAG_SEQUENCE, &[
+                &der_tlv(asn1::TAG_OID, asn1::OID_SUBJECT_ALT_NAME),
+                &der_constructed(asn1::TAG_SET, &[&acp_san()]),
+            ]),
+        ]);
+        let attrs = CsrAttributes::decode(&csrattrs).unwrap();
+        assert_eq!(attrs.extensions, vec![extension]);
+        assert!(!attrs.server_keygen);
+    }
+
+    #[test]
+    fn server_keygen_requested() {
+        let csrattrs = der_constructed(asn1::TAG_SEQUENCE, &[
+            &der_tlv(asn1::TAG_OID, asn1::OID_ASYMM_DECRYPT_KEY_ID),
+        ]);
+        assert!(CsrAttributes::decode(&csrattrs).unwrap().server_keygen);
+    }
+
+    #[test]
+    fn unsupported_curve() {
+        let csrattrs = der_constructed(asn1::TAG_SEQUENCE, &[
+            &der_constructed(asn1::TAG_SEQUENCE, &[
+                &der_tlv(asn1::TAG_OID, asn1::OID_EC_PUBLIC_KEY),
+                &der_constructed(asn1::TAG_SET, &[&der_tlv(asn1::TAG_OID, asn1::OID_ED25519)]),
+            ]),
+        ]);
+        assert!(matches!(CsrAttributes::decode(&csrattrs), Err(CsrError::UnsupportedKey)));
+    }
+
+    #[test]
+    fn signed_csr() {
+        let attrs = CsrAttributes {
+            key_type: Some(KeyType::Ec(EcGroupId::SecP384R1)),
+            ..CsrAttributes::default()
+        };
+        let mut pk = generate_key(attrs.key_type.unwrap()).unwrap();
+        let csr = build_csr(&mut pk, b"00-D0-E5-F2-00-02", &attrs, None).unwrap();
+
+        let mut fields = DerReader::new(&csr).expect(asn1::TAG_SEQUENCE).unwrap().reader();
+        let info = fields.expect(asn1::TAG_SEQUENCE).unwrap();
+        let alg = fields.expect(asn1::TAG_SEQUENCE).unwrap();
+        let sig = fields.expect(asn1::TAG_BIT_STRING).unwrap();
+        assert_eq!(alg.reader().expect(asn1::TAG_OID).unwrap().value, asn1::OID_ECDSA_SHA384);
+
+        let mut info_fields = info.reader();
+        info_fields.expect(asn1::TAG_INTEGER).unwrap();
+        let subject = info_fields.expect(asn1::TAG_SEQUENCE).unwrap();
+        let sn = asn1::find_name_attribute(&subject, asn1::OID_SERIAL_NUMBER).unwrap().unwrap();
+        assert_eq!(sn.tag, asn1::TAG_PRINTABLE_STRING);
+        let spki = info_fields.expect(asn1::TAG_SEQUENCE).unwrap();
+
+        let mut hash = vec![0u8; 48];
+        mbedtls_hash::Md::hash(mbedtls_hash::Type::Sha384, info.raw, &mut hash).unwrap();
+        let mut public = Pk::from_public_key(spki.raw).unwrap();
+        public.verify(mbedtls_hash::Type::Sha384, &hash, &sig.value[1..]).unwrap();
+    }
+}
+/*
+ * Local Variables:
+ * mode: rust
+ * compile-command: "cd .. && cargo build"
+ * End:
+ */
@@ -1,11 +1,29 @@
 use minerva_voucher::Voucher;
 pub use minerva_voucher::{VoucherError, Sign, Validate, SignatureAlgorithm, attr::*};
 use super::utils;
+use super::asn1;
+use super::cbor::CborValue;
+use super::cose;
+use super::cms;
+use super::ed25519;
+use super::json_voucher;
+use super::jws;
 use std::convert::TryFrom;
 
 //
 
-pub struct CustomVoucher(Voucher);
+/// The attributes live in the minerva_voucher `Voucher`.  When the COSE
+/// algorithm is one that minerva_voucher has no name for (EdDSA), the
+/// COSE_Sign1 is kept here as well, and signed or validated locally.
+/// CMS and JWS signed JSON vouchers are kept the same way.
+pub struct CustomVoucher(Voucher, Envelope);
+
+enum Envelope {
+    Minerva,
+    Sign1(cose::Sign1),
+    Cms(cms::SignedData, Vec<u8>),
+    Jws(jws::Jws, Vec<u8>),
+}
 
 impl core::ops::Deref for CustomVoucher {
     type Target = Voucher;
@@ -19,16 +37,130 @@ impl core::ops::DerefMut for CustomVoucher {
 impl TryFrom<&[u8]> for CustomVoucher {
     type Error = VoucherError;
     fn try_from(raw: &[u8]) -> Result<Self, Self::Error> {
-        Ok(Self(Voucher::try_from(raw)?))
+        match cose::Sign1::decode(raw) {
+            Ok(sign1) if sign1.alg() == Some(cose::ALG_EDDSA) => {
+                // let minerva_voucher decode the attributes from a copy
+                // carrying an algorithm that it knows about
+                let placeholder = sign1.with_alg(cose::ALG_ES256).encode();
+                Ok(Self(Voucher::try_from(placeholder.as_slice())?, Envelope::Sign1(sign1)))
+            },
+            _ => Ok(Self(Voucher::try_from(raw)?, Envelope::Minerva)),
+        }
+    }
+}
+
+/// every signature algorithm a voucher or voucher-request can use
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VoucherAlgorithm {
+    ES256,
+    ES384,
+    ES512,
+    PS256,
+    EdDSA,
+}
+
+impl VoucherAlgorithm {
+    /// the minerva_voucher name, if it has one
+    pub fn signature_algorithm(&self) -> Option<SignatureAlgorithm> {
+        match self {
+            VoucherAlgorithm::ES256 => Some(SignatureAlgorithm::ES256),
+            VoucherAlgorithm::ES384 => Some(SignatureAlgorithm::ES384),
+            VoucherAlgorithm::ES512 => Some(SignatureAlgorithm::ES512),
+            VoucherAlgorithm::PS256 => Some(SignatureAlgorithm::PS256),
+            VoucherAlgorithm::EdDSA => None,
+        }
     }
 }
 
 impl CustomVoucher {
-    pub fn new_vrq() -> Self { Self(Voucher::new_vrq()) }
+    pub fn new_vrq() -> Self { Self(Voucher::new_vrq(), Envelope::Minerva) }
+
+    /// a JSON voucher or voucher-request in CMS SignedData
+    pub fn from_cms(raw: &[u8]) -> Result<Self, VoucherError> {
+        let signed = cms::SignedData::decode(raw).or(Err(VoucherError::MalformedInput))?;
+        if signed.content_type != cms::OID_ANIMA_JSON_VOUCHER {
+            return Err(VoucherError::MalformedInput);
+        }
+        let voucher = json_voucher::from_json(&signed.content)?;
+        Ok(Self(voucher, Envelope::Cms(signed, raw.to_vec())))
+    }
+
+    /// a JSON voucher or voucher-request in a JWS
+    pub fn from_jws(raw: &[u8]) -> Result<Self, VoucherError> {
+        let signed = jws::Jws::decode(raw)?;
+        let voucher = json_voucher::from_json(&signed.payload)?;
+        Ok(Self(voucher, Envelope::Jws(signed, raw.to_vec())))
+    }
+
     pub fn set(&mut self, attr: Attr) -> &mut Self {
         self.0.set(attr);
         self
     }
+
+    pub fn sign_as(&mut self, privkey_pem: &[u8], alg: VoucherAlgorithm) -> Result<&mut Self, VoucherError> {
+        match alg.signature_algorithm() {
+            Some(sig_alg) => {
+                self.1 = Envelope::Minerva;
+                self.sign(privkey_pem, sig_alg)
+            },
+            None => self.sign_eddsa(privkey_pem),
+        }
+    }
+
+    fn sign_eddsa(&mut self, privkey_pem: &[u8]) -> Result<&mut Self, VoucherError> {
+        let seed = utils::pem_to_der(privkey_pem)
+            .and_then(|der| ed25519::private_key_from_pkcs8(&der))
+            .ok_or(VoucherError::SigningFailed)?;
+
+        // minerva_voucher encodes the payload, the algorithm is replaced below
+        let (_, sig_struct) = self.0.to_sign(SignatureAlgorithm::ES256);
+        let payload = cose::payload_from_sig_structure(sig_struct)
+            .ok_or(VoucherError::SigningFailed)?;
+
+        let protected = cose::protected_header(cose::ALG_EDDSA);
+        let signature = ed25519::sign(&seed, &cose::sig_structure(&protected, &payload))
+            .ok_or(VoucherError::SigningFailed)?;
+
+        self.1 = Envelope::Sign1(cose::Sign1 {
+            protected:   protected,
+            unprotected: CborValue::Map(Vec::new()),
+            payload:     payload,
+            signature:   signature,
+            tagged:      true,
+        });
+        Ok(self)
+    }
+
+    /// sign as JSON in CMS SignedData, carrying the signer certificate
+    pub fn sign_cms(&mut self, privkey_pem: &[u8], cert_der: &[u8], alg: VoucherAlgorithm) -> Result<&mut Self, VoucherError> {
+        let json = json_voucher::to_json(&self.0);
+        let der = cms::sign(cms::OID_ANIMA_JSON_VOUCHER, &json, cert_der, privkey_pem, alg)
+            .map_err(|e| {
+                println!("sign_cms(): {}", e);
+                VoucherError::SigningFailed
+            })?;
+        let signed = cms::SignedData::decode(&der).or(Err(VoucherError::SigningFailed))?;
+        self.1 = Envelope::Cms(signed, der);
+        Ok(self)
+    }
+
+    /// sign as JSON in a JWS, with the signer certificate in x5c
+    pub fn sign_jws(&mut self, privkey_pem: &[u8], cert_der: &[u8], alg: VoucherAlgorithm) -> Result<&mut Self, VoucherError> {
+        let json = json_voucher::to_json(&self.0);
+        let raw = jws::sign(&json, &[cert_der.to_vec()], privkey_pem, alg)?;
+        let signed = jws::Jws::decode(&raw).or(Err(VoucherError::SigningFailed))?;
+        self.1 = Envelope::Jws(signed, raw);
+        Ok(self)
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, VoucherError> {
+        match &self.1 {
+            Envelope::Minerva => self.0.serialize(),
+            Envelope::Sign1(sign1) => Ok(sign1.encode()),
+            Envelope::Cms(_, der) => Ok(der.clone()),
+            Envelope::Jws(_, raw) => Ok(raw.clone()),
+        }
+    }
 }
 
 //
@@ -36,11 +168,12 @@ impl CustomVoucher {
 type CustomError = mbedtls::Error;
 const ERROR_ASN1_FAILED: i32 = -1;
 
-use mbedtls::pk::{EcGroup, EcGroupId, Pk, ECDSA_MAX_LEN};
+use mbedtls::pk::{EcGroup, EcGroupId, Pk, Options, RsaPadding, Type as PkType, ECDSA_MAX_LEN};
 use mbedtls::ecp::EcPoint;
+use mbedtls::bignum::Mpi;
 use mbedtls::x509::certificate::Certificate;
 use mbedtls::hash as mbedtls_hash;
-use super::support_rand::test_rng;
+use super::support_rand;
 
 //
 
@@ -60,20 +193,87 @@ fn sign_with_rust_mbedtls(
     (sig_out, sig_struct): (&mut Vec<u8>, &[u8])
 ) -> Result<(), CustomError> {
     let (ref hash, md_ty) = compute_digest(sig_struct, &alg)?;
-    let mut sig = vec![0u8; ECDSA_MAX_LEN];
-    let sig_len = Pk::from_private_key(&utils::null_terminate_bytes!(privkey_pem), None)?
-        .sign_deterministic(md_ty, &hash, &mut sig, &mut test_rng())?;
-    sig.truncate(sig_len);
+    let mut pk = Pk::from_private_key(&utils::null_terminate_bytes!(privkey_pem), None)?;
+
+    let sig = if is_rsa_pss(&alg) {
+        use_pss(&mut pk);
+        let mut sig = vec![0u8; (pk.len() + 7) / 8];
+        let sig_len = pk.sign(md_ty, &hash, &mut sig, &mut support_rand::signing_rng()?)?;
+        sig.truncate(sig_len);
+        sig
+    } else {
+        let mut sig = vec![0u8; ECDSA_MAX_LEN];
+        let sig_len = pk.sign_deterministic(md_ty, &hash, &mut sig, &mut support_rand::signing_rng()?)?;
+        sig.truncate(sig_len);
+        // COSE wants r||s, not what mbedtls produces
+        utils::ecdsa_der_to_raw(&sig, ecdsa_field_len(&alg))
+            .or(Err(CustomError::Other(ERROR_ASN1_FAILED)))?
+    };
 
     *sig_out = sig;
 
     Ok(())
 }
 
+fn ecdsa_field_len(alg: &SignatureAlgorithm) -> usize {
+    match alg {
+        SignatureAlgorithm::ES384 => 48,
+        SignatureAlgorithm::ES512 => 66,
+        _ => 32,
+    }
+}
+
+fn is_rsa_pss(alg: &SignatureAlgorithm) -> bool {
+    match alg {
+        SignatureAlgorithm::PS256 => true,
+        _ => false,
+    }
+}
+
+// COSE PS256 (RFC8230) is RSASSA-PSS with SHA-256 and MGF1 with SHA-256,
+// which is also what cms.rs puts in its RSASSA-PSS AlgorithmIdentifier
+pub fn use_pss(pk: &mut Pk) {
+    pk.set_options(Options::Rsa {
+        padding: RsaPadding::Pkcs1V21 { mgf: mbedtls_hash::Type::Sha256 },
+    });
+}
+
+fn verify_with(
+    pk: &mut Pk,
+    alg: &SignatureAlgorithm,
+    md_ty: mbedtls_hash::Type,
+    hash: &[u8],
+    signature: &[u8]
+) -> Result<bool, CustomError> {
+    if is_rsa_pss(alg) {
+        if pk.pk_type() != PkType::Rsa {
+            return Ok(false);
+        }
+        use_pss(pk);
+    }
+    pk.verify(md_ty, hash, signature).and(Ok(true))
+}
+
 //
 
 impl Validate for CustomVoucher {
     fn validate(&self, pem: Option<&[u8]>) -> Result<&Self, VoucherError> {
+        let valid = match &self.1 {
+            Envelope::Minerva => None,
+            Envelope::Sign1(sign1) => {
+                let (signer_cert, _, _) = self.to_validate();
+                Some(validate_eddsa(pem, signer_cert, sign1))
+            },
+            // the certificates carried inside are not trusted on their own
+            Envelope::Cms(signed, _) => Some(pem.map_or(false, |pem| signed.verify(pem))),
+            Envelope::Jws(signed, _) => Some(pem.map_or(false, |pem| signed.verify(pem))),
+        };
+        match valid {
+            Some(true) => return Ok(self),
+            Some(false) => return Err(VoucherError::ValidationFailed),
+            None => {},
+        }
+
         match validate_with_rust_mbedtls(pem, self.to_validate()) {
             Ok(true) => Ok(self),
             Ok(false) => Err(VoucherError::ValidationFailed),
@@ -89,35 +289,53 @@ fn validate_with_rust_mbedtls(
     if sig_alg.is_none() { return Ok(false); }
     let (signature, alg) = sig_alg.unwrap();
 
-    let ref signature = if utils::is_asn1_signature(signature) {
+    // ECDSA signatures in COSE are r||s, but mbedtls wants ASN.1.
+    // Some older implementations sent ASN.1, so accept that too.
+    let ref signature = if is_rsa_pss(alg) {
+        signature.to_vec()
+    } else if signature.len() == 2 * ecdsa_field_len(alg) {
+        utils::ecdsa_raw_to_der(signature).or(Err(CustomError::Other(ERROR_ASN1_FAILED)))?
+    } else if utils::is_der_signature(signature) {
         signature.to_vec()
     } else {
-        utils::asn1_signature_from(signature).or(Err(CustomError::Other(ERROR_ASN1_FAILED)))?
+        return Ok(false);
     };
 
     let (ref hash, md_ty) = compute_digest(msg, alg)?;
 
     if let Some(pem) = pem {
-        let pem = &utils::null_terminate_bytes!(pem);
+        if !pem.starts_with(b"-----BEGIN") {
+            // a DER encoded certificate
+            let mut cert = Certificate::from_der(pem)?;
+            return verify_with(cert.public_key_mut(), alg, md_ty, hash, signature);
+        }
 
-        if let Ok(mut pk) = Pk::from_private_key(pem, None) {
-            return pk.verify(md_ty, hash, signature).and(Ok(true));
+        // a certificate, or a bare public key; never a private key
+        let is_public_key = pem.starts_with(b"-----BEGIN PUBLIC KEY-----");
+        let pem = &utils::null_terminate_bytes!(pem);
+        if is_public_key {
+            let mut pk = Pk::from_public_key(pem)?;
+            return verify_with(&mut pk, alg, md_ty, hash, signature);
         }
 
-        Certificate::from_pem(pem)?
-            .public_key_mut()
-            .verify(md_ty, hash, signature)
-            .and(Ok(true))
+        let mut cert = Certificate::from_pem(pem)?;
+        verify_with(cert.public_key_mut(), alg, md_ty, hash, signature)
     } else if let Some(cert) = signer_cert {
-        let grp = EcGroup::new(EcGroupId::SecP256R1)?;
-
-        let prefix = *cert.get(0).unwrap();
-        if prefix == 0x02 || prefix == 0x03 {
-            // "Compressed point, which mbedtls does not understand"
-            // according to 'src/ecp/mod.rs' of the `rust-mbedtls` crate
-            println!("validate(): warning: `cert` cannot be processed by vanilla mbedtls");
+        if is_rsa_pss(alg) {
+            // an RSA signer key can only be a SubjectPublicKeyInfo
+            let mut pk = Pk::from_public_key(cert)?;
+            return verify_with(&mut pk, alg, md_ty, hash, signature);
         }
-        let pt = EcPoint::from_binary(&grp, cert)?;
+
+        let curve = match signer_curve(alg, cert) {
+            Some(curve) => curve,
+            None => {
+                println!("validate(): signer key of {} bytes is not on the curve of the algorithm", cert.len());
+                return Err(CustomError::EcpBadInputData);
+            }
+        };
+        let grp = EcGroup::new(curve)?;
+        let pt = ec_point_from(&grp, curve, cert)?;
 
         Pk::public_from_ec_components(grp.clone(), pt)?
             .verify(md_ty, hash, signature)
@@ -128,14 +346,155 @@ fn validate_with_rust_mbedtls(
     }
 }
 
+/// the curve that goes with the COSE algorithm, as long as the raw signer
+/// key, compressed or not, is the length of a point on it
+fn signer_curve(alg: &SignatureAlgorithm, key: &[u8]) -> Option<EcGroupId> {
+    let curve = match alg {
+        SignatureAlgorithm::ES256 => EcGroupId::SecP256R1,
+        SignatureAlgorithm::ES384 => EcGroupId::SecP384R1,
+        SignatureAlgorithm::ES512 => EcGroupId::SecP521R1,
+        _ => return None,
+    };
+    let len = field_len(curve);
+    if key.len() == 1 + len || key.len() == 1 + 2 * len {
+        Some(curve)
+    } else {
+        None
+    }
+}
+
+fn field_len(curve: EcGroupId) -> usize {
+    match curve {
+        EcGroupId::SecP384R1 => 48,
+        EcGroupId::SecP521R1 => 66,
+        _ => 32,
+    }
+}
+
+fn ec_point_from(grp: &EcGroup, curve: EcGroupId, key: &[u8]) -> Result<EcPoint, CustomError> {
+    match key.first() {
+        Some(0x02) | Some(0x03) => decompress_point(grp, curve, key),
+        Some(_) => EcPoint::from_binary(grp, key),
+        None => Err(CustomError::EcpBadInputData),
+    }
+}
+
+/*
+ * SEC1 section 2.3.4: mbedtls does not understand compressed points.
+ * All the curves here have a = -3 and p = 3 mod 4, so
+ * y = (x^3 - 3x + b) ^ ((p + 1) / 4) mod p, then pick y by parity.
+ */
+fn decompress_point(grp: &EcGroup, curve: EcGroupId, key: &[u8]) -> Result<EcPoint, CustomError> {
+    if key.len() != 1 + field_len(curve) {
+        return Err(CustomError::EcpBadInputData);
+    }
+    let y_odd = key[0] == 0x03;
+
+    let p = grp.p()?;
+    let b = grp.b()?;
+    let x = Mpi::from_binary(&key[1..])?;
+    if x >= p {
+        return Err(CustomError::EcpBadInputData);
+    }
+
+    let x3 = (&(&x * &x)? * &x)?;
+    let three_x = (&x * &Mpi::new(3)?)?;
+    let y2 = (&(&(&x3 - &three_x)? + &b)? % &p)?;
+
+    let exp = (&(&p + &Mpi::new(1)?)? / &Mpi::new(4)?)?;
+    let mut y = y2.modpow(&exp, &p)?;
+
+    // no square root: not a point on the curve
+    if (&(&y * &y)? % &p)? != y2 {
+        return Err(CustomError::EcpBadInputData);
+    }
+    if y.get_bit(0) != y_odd {
+        y = (&p - &y)?;
+    }
+
+    EcPoint::from_components(x, y)
+}
+
+/// the signer key is taken from the MASA certificate (PEM or DER) if given,
+/// otherwise it is the raw 32 byte key from the voucher
+fn validate_eddsa(pem: Option<&[u8]>, signer_cert: Option<&[u8]>, sign1: &cose::Sign1) -> bool {
+    let public = if let Some(pem) = pem {
+        match ed25519_public_from_cert(pem) {
+            Some(public) => public.to_vec(),
+            None => return false,
+        }
+    } else if let Some(key) = signer_cert {
+        key.to_vec()
+    } else {
+        println!("validate(): Neither external masa cert nor signer cert is available.");
+        return false;
+    };
+
+    ed25519::verify(&public, &sign1.to_be_signed(), &sign1.signature)
+}
+
+fn ed25519_public_from_cert(cert: &[u8]) -> Option<[u8; ed25519::KEY_LEN]> {
+    let der = utils::cert_der(cert)?;
+    let parts = asn1::parse_certificate(&der).ok()?;
+    ed25519::public_key_from_spki(parts.spki.raw)
+}
+
+/*
+ * signatures over arbitrary bytes, in the form that COSE and JWS (RFC7518)
+ * share: ECDSA as r||s, PS256 as RSASSA-PSS, EdDSA as Ed25519.
+ */
+pub fn sign_detached(privkey_pem: &[u8], alg: VoucherAlgorithm, msg: &[u8]) -> Result<Vec<u8>, VoucherError> {
+    let sig_alg = match alg.signature_algorithm() {
+        Some(sig_alg) => sig_alg,
+        None => {
+            return utils::pem_to_der(privkey_pem)
+                .and_then(|der| ed25519::private_key_from_pkcs8(&der))
+                .and_then(|seed| ed25519::sign(&seed, msg))
+                .ok_or(VoucherError::SigningFailed);
+        }
+    };
+
+    let mut sig = Vec::new();
+    sign_with_rust_mbedtls(privkey_pem, sig_alg, (&mut sig, msg))
+        .or(Err(VoucherError::SigningFailed))?;
+    Ok(sig)
+}
+
+/// `cert` is the signer certificate, PEM or DER
+pub fn verify_detached(cert: &[u8], alg: VoucherAlgorithm, msg: &[u8], signature: &[u8]) -> bool {
+    match alg.signature_algorithm() {
+        Some(sig_alg) => {
+            let sig_alg = Some((signature, &sig_alg));
+            validate_with_rust_mbedtls(Some(cert), (None, sig_alg, msg)).unwrap_or(false)
+        },
+        None => match ed25519_public_from_cert(cert) {
+            Some(public) => ed25519::verify(&public, msg, signature),
+            None => false,
+        },
+    }
+}
+
 //
 
+/// COSE algorithm to use with a given private or public key
+pub fn signature_algorithm_for(pk: &Pk) -> Option<VoucherAlgorithm> {
+    if pk.pk_type() == PkType::Rsa {
+        return Some(VoucherAlgorithm::PS256);
+    }
+    match pk.curve() {
+        Ok(EcGroupId::SecP256R1) => Some(VoucherAlgorithm::ES256),
+        Ok(EcGroupId::SecP384R1) => Some(VoucherAlgorithm::ES384),
+        Ok(EcGroupId::SecP521R1) => Some(VoucherAlgorithm::ES512),
+        _ => None,
+    }
+}
+
 fn compute_digest(msg: &[u8], alg: &SignatureAlgorithm) -> Result<(Vec<u8>, mbedtls_hash::Type), CustomError> {
     let (md_type, digest_len) = match *alg {
         SignatureAlgorithm::ES256 => (mbedtls_hash::Type::Sha256, 32),
         SignatureAlgorithm::ES384 => (mbedtls_hash::Type::Sha384, 48),
         SignatureAlgorithm::ES512 => (mbedtls_hash::Type::Sha512, 64),
-        SignatureAlgorithm::PS256 => unimplemented!("handle PS256"),
+        SignatureAlgorithm::PS256 => (mbedtls_hash::Type::Sha256, 32),
     };
 
     let mut digest = vec![0u8; digest_len];
@@ -143,3 +502,96 @@ fn compute_digest(msg: &[u8], alg: &SignatureAlgorithm) -> Result<(Vec<u8>, mbed
 
     Ok((digest, md_type))
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // the P-256 base point, compressed, and its y coordinate
+    static G_COMPRESSED: [u8; 33] = [
+        0x03,
+        0x6b, 0x17, 0xd1, 0xf2, 0xe1, 0x2c, 0x42, 0x47, 0xf8, 0xbc, 0xe6, 0xe5, 0x63, 0xa4, 0x40, 0xf2,
+        0x77, 0x03, 0x7d, 0x81, 0x2d, 0xeb, 0x33, 0xa0, 0xf4, 0xa1, 0x39, 0x45, 0xd8, 0x98, 0xc2, 0x96,
+    ];
+    static G_Y: [u8; 32] = [
+        0x4f, 0xe3, 0x42, 0xe2, 0xfe, 0x1a, 0x7f, 0x9b, 0x8e, 0xe7, 0xeb, 0x4a, 0x7c, 0x0f, 0x9e, 0x16,
+        0x2b, 0xce, 0x33, 0x57, 0x6b, 0x31, 0x5e, 0xce, 0xcb, 0xb6, 0x40, 0x68, 0x37, 0xbf, 0x51, 0xf5,
+    ];
+
+    // the P-384 and P-521 base points, likewise
+    static G384_COMPRESSED: [u8; 49] = [
+        0x03,
+        0xaa, 0x87, 0xca, 0x22, 0xbe, 0x8b, 0x05, 0x37, 0x8e, 0xb1, 0xc7, 0x1e, 0xf3, 0x20, 0xad, 0x74,
+        0x6e, 0x1d, 0x3b, 0x62, 0x8b, 0xa7, 0x9b, 0x98, 0x59, 0xf7, 0x41, 0xe0, 0x82, 0x54, 0x2a, 0x38,
+        0x55, 0x02, 0xf2, 0x5d, 0xbf, 0x55, 0x29, 0x6c, 0x3a, 0x54, 0x5e, 0x38, 0x72, 0x76, 0x0a, 0xb7,
+    ];
+    static G384_Y: [u8; 48] = [
+        0x36, 0x17, 0xde, 0x4a, 0x96, 0x26, 0x2c, 0x6f, 0x5d, 0x9e, 0x98, 0xbf, 0x92, 0x92, 0xdc, 0x29,
+        0xf8, 0xf4, 0x1d, 0xbd, 0x28, 0x9a, 0x14, 0x7c, 0xe9, 0xda, 0x31, 0x13, 0xb5, 0xf0, 0xb8, 0xc0,
+        0x0a, 0x60, 0xb1, 0xce, 0x1d, 0x7e, 0x81, 0x9d, 0x7a, 0x43, 0x1d, 0x7c, 0x90, 0xea, 0x0e, 0x5f,
+    ];
+    static G521_COMPRESSED: [u8; 67] = [
+        0x02,
+        0x00, 0xc6, 0x85, 0x8e, 0x06, 0xb7, 0x04, 0x04, 0xe9, 0xcd, 0x9e, 0x3e, 0xcb, 0x66, 0x23, 0x95,
+        0xb4, 0x42, 0x9c, 0x64, 0x81, 0x39, 0x05, 0x3f, 0xb5, 0x21, 0xf8, 0x28, 0xaf, 0x60, 0x6b, 0x4d,
+        0x3d, 0xba, 0xa1, 0x4b, 0x5e, 0x77, 0xef, 0xe7, 0x59, 0x28, 0xfe, 0x1d, 0xc1, 0x27, 0xa2, 0xff,
+        0xa8, 0xde, 0x33, 0x48, 0xb3, 0xc1, 0x85, 0x6a, 0x42, 0x9b, 0xf9, 0x7e, 0x7e, 0x31, 0xc2, 0xe5,
+        0xbd, 0x66,
+    ];
+    static G521_Y: [u8; 66] = [
+        0x01, 0x18, 0x39, 0x29, 0x6a, 0x78, 0x9a, 0x3b, 0xc0, 0x04, 0x5c, 0x8a, 0x5f, 0xb4, 0x2c, 0x7d,
+        0x1b, 0xd9, 0x98, 0xf5, 0x44, 0x49, 0x57, 0x9b, 0x44, 0x68, 0x17, 0xaf, 0xbd, 0x17, 0x27, 0x3e,
+        0x66, 0x2c, 0x97, 0xee, 0x72, 0x99, 0x5e, 0xf4, 0x26, 0x40, 0xc5, 0x50, 0xb9, 0x01, 0x3f, 0xad,
+        0x07, 0x61, 0x35, 0x3c, 0x70, 0x86, 0xa2, 0x72, 0xc2, 0x40, 0x88, 0xbe, 0x94, 0x76, 0x9f, 0xd1,
+        0x66, 0x50,
+    ];
+
+    fn decompress_generator(curve: EcGroupId, compressed: &[u8], y: &[u8]) {
+        let grp = EcGroup::new(curve).unwrap();
+        let len = field_len(curve);
+        let pt = ec_point_from(&grp, curve, compressed).unwrap();
+        let uncompressed = pt.to_binary(&grp, false).unwrap();
+        assert_eq!(&uncompressed[1..1 + len], &compressed[1..]);
+        assert_eq!(&uncompressed[1 + len..], y);
+
+        // the other y for the same x
+        let mut other = compressed.to_vec();
+        other[0] ^= 0x01;
+        let pt = ec_point_from(&grp, curve, &other).unwrap();
+        assert_ne!(&pt.to_binary(&grp, false).unwrap()[1 + len..], y);
+    }
+
+    #[test]
+    fn decompress_generators() {
+        decompress_generator(EcGroupId::SecP256R1, &G_COMPRESSED, &G_Y);
+        decompress_generator(EcGroupId::SecP384R1, &G384_COMPRESSED, &G384_Y);
+        decompress_generator(EcGroupId::SecP521R1, &G521_COMPRESSED, &G521_Y);
+    }
+
+    #[test]
+    fn curve_follows_algorithm() {
+        assert_eq!(signer_curve(&SignatureAlgorithm::ES256, &G_COMPRESSED), Some(EcGroupId::SecP256R1));
+        assert_eq!(signer_curve(&SignatureAlgorithm::ES384, &G384_COMPRESSED), Some(EcGroupId::SecP384R1));
+        assert_eq!(signer_curve(&SignatureAlgorithm::ES512, &G521_COMPRESSED), Some(EcGroupId::SecP521R1));
+        // a P-256 key under ES384, or the other way around
+        assert_eq!(signer_curve(&SignatureAlgorithm::ES384, &G_COMPRESSED), None);
+        assert_eq!(signer_curve(&SignatureAlgorithm::ES256, &G384_COMPRESSED), None);
+        assert_eq!(signer_curve(&SignatureAlgorithm::PS256, &G_COMPRESSED), None);
+    }
+
+    #[test]
+    fn reject_bad_compressed_point() {
+        let grp = EcGroup::new(EcGroupId::SecP256R1).unwrap();
+        assert!(ec_point_from(&grp, EcGroupId::SecP256R1, &G_COMPRESSED[..32]).is_err());
+        assert!(ec_point_from(&grp, EcGroupId::SecP256R1, &[]).is_err());
+
+        // x = 1 is not on P-256, and x must be less than p
+        let mut x = [0u8; 33];
+        x[0] = 0x02;
+        x[32] = 0x01;
+        assert!(ec_point_from(&grp, EcGroupId::SecP256R1, &x).is_err());
+        let mut x = [0xffu8; 33];
+        x[0] = 0x02;
+        assert!(ec_point_from(&grp, EcGroupId::SecP256R1, &x).is_err());
+    }
+}
@@ -0,0 +1,130 @@
+/*
+ * Copyright [2021] <mcr@sandelman.ca>
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+ *
+ */
+
+/*
+ * Ed25519 (RFC8032) keys and signatures.  The mbedtls 2.x that rust-mbedtls
+ * wraps has no EdDSA at all, so this is done in software with ed25519-dalek.
+ * Keys are found in the RFC8410 PKCS#8 and SubjectPublicKeyInfo wrappings.
+ */
+
+use std::convert::TryFrom;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+
+use crate::asn1::{self, DerReader};
+
+pub const KEY_LEN: usize = 32;
+
+pub fn is_ed25519_algorithm(alg_id: &asn1::Tlv) -> bool {
+    let mut fields = alg_id.reader();
+    match fields.expect(asn1::TAG_OID) {
+        Ok(oid) => oid.value == asn1::OID_ED25519,
+        Err(_) => false,
+    }
+}
+
+/// the 32 byte seed from an unencrypted PKCS#8 OneAsymmetricKey
+pub fn private_key_from_pkcs8(der: &[u8]) -> Option<[u8; KEY_LEN]> {
+    let pkcs8 = DerReader::new(der).expect(asn1::TAG_SEQUENCE).ok()?;
+    let mut fields = pkcs8.reader();
+    fields.expect(asn1::TAG_INTEGER).ok()?;
+    if !is_ed25519_algorithm(&fields.expect(asn1::TAG_SEQUENCE).ok()?) {
+        return None;
+    }
+    let outer = fields.expect(asn1::TAG_OCTET_STRING).ok()?;
+    let seed = DerReader::new(outer.value).expect(asn1::TAG_OCTET_STRING).ok()?;
+    <[u8; KEY_LEN]>::try_from(seed.value).ok()
+}
+
+/// the 32 byte public key from a SubjectPublicKeyInfo
+pub fn public_key_from_spki(spki: &[u8]) -> Option<[u8; KEY_LEN]> {
+    let spki = DerReader::new(spki).expect(asn1::TAG_SEQUENCE).ok()?;
+    let mut fields = spki.reader();
+    if !is_ed25519_algorithm(&fields.expect(asn1::TAG_SEQUENCE).ok()?) {
+        return None;
+    }
+    let bits = fields.expect(asn1::TAG_BIT_STRING).ok()?;
+    match bits.value.split_first() {
+        Some((0, key)) => <[u8; KEY_LEN]>::try_from(key).ok(),
+        _ => None,
+    }
+}
+
+fn keypair(seed: &[u8; KEY_LEN]) -> Option<Keypair> {
+    let secret = SecretKey::from_bytes(seed).ok()?;
+    let public = PublicKey::from(&secret);
+    Some(Keypair { secret: secret, public: public })
+}
+
+pub fn public_key_from_seed(seed: &[u8; KEY_LEN]) -> Option<[u8; KEY_LEN]> {
+    keypair(seed).map(|kp| kp.public.to_bytes())
+}
+
+pub fn sign(seed: &[u8; KEY_LEN], msg: &[u8]) -> Option<Vec<u8>> {
+    keypair(seed).map(|kp| kp.sign(msg).to_bytes().to_vec())
+}
+
+pub fn verify(public: &[u8], msg: &[u8], signature: &[u8]) -> bool {
+    let public = match PublicKey::from_bytes(public) {
+        Ok(public) => public,
+        Err(_) => return false,
+    };
+    match Signature::try_from(signature) {
+        Ok(signature) => public.verify(msg, &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::test_fixtures::{ed25519_cert, ed25519_key_pem, ED25519_SEED as SEED};
+    use crate::utils;
+
+    // RFC8032 section 7.1, TEST 2
+    static PUBLIC: [u8; 32] = [
+        0x3d, 0x40, 0x17, 0xc3, 0xe8, 0x43, 0x89, 0x5a, 0x92, 0xb7, 0x0a, 0xa7, 0x4d, 0x1b, 0x7e, 0xbc,
+        0x9c, 0x98, 0x2c, 0xcf, 0x2e, 0xc4, 0x96, 0x8c, 0xc0, 0xcd, 0x55, 0xf1, 0x2a, 0xf4, 0x66, 0x0c,
+    ];
+
+    #[test]
+    fn rfc8032_vector() {
+        assert_eq!(public_key_from_seed(&SEED), Some(PUBLIC));
+
+        let sig = sign(&SEED, &[0x72]).unwrap();
+        assert_eq!(&sig[..4], &[0x92, 0xa0, 0x09, 0xa9]);
+        assert!(verify(&PUBLIC, &[0x72], &sig));
+        assert!(!verify(&PUBLIC, &[0x73], &sig));
+    }
+
+    #[test]
+    fn pkcs8_and_spki() {
+        let pkcs8 = utils::pem_to_der(&ed25519_key_pem()).unwrap();
+        assert_eq!(private_key_from_pkcs8(&pkcs8), Some(SEED));
+
+        let cert = ed25519_cert();
+        let spki = asn1::parse_certificate(&cert).unwrap().spki.raw;
+        assert_eq!(spki.len(), 44);
+        assert_eq!(public_key_from_spki(spki), Some(PUBLIC));
+    }
+}
+
+/*
+ * Local Variables:
+ * mode: rust
+ * compile-command: "cd .. && cargo build"
+ * End:
+ */
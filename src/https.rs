@@ -0,0 +1,175 @@
+/*
+ * Copyright [2021] <mcr@sandelman.ca>
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+ *
+ */
+
+/*
+ * The HTTPS requests to one registrar, sent with ureq over the
+ * MbedTlsConnector.  The voucher-request has to carry the registrar
+ * certificate (RFC8995 section 5.2), so the first TLS handshake is done
+ * before ureq makes any request, and ureq is then handed that connection.
+ * ureq keeps it for the requests that follow; should it have to connect
+ * again, the connector insists on the same registrar certificate.
+ */
+
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::mbedtls_connector::MbedTlsConnector;
+
+// no response from a registrar needs more than this, and the provisional
+// registrar is not trusted with our memory
+pub const MAX_BODY: u64 = 4 * 1024 * 1024;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const IO_TIMEOUT:      Duration = Duration::from_secs(20);
+
+pub struct HttpResponse {
+    pub status:  u16,
+    pub headers: Vec<(String, String)>,
+    pub body:    Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    /// the whole of a ureq response, refusing bodies over MAX_BODY
+    fn read(resp: ureq::Response) -> io::Result<HttpResponse> {
+        let status = resp.status();
+        let headers = resp.headers_names().into_iter()
+            .filter_map(|name| {
+                let value = resp.header(&name)?.to_string();
+                Some((name, value))
+            })
+            .collect();
+
+        let mut body = Vec::new();
+        resp.into_reader().take(MAX_BODY + 1).read_to_end(&mut body)?;
+        if body.len() as u64 > MAX_BODY {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "HTTP response body too large"));
+        }
+        Ok(HttpResponse { status: status, headers: headers, body: body })
+    }
+}
+
+pub struct Session {
+    agent:     ureq::Agent,
+    connector: Arc<MbedTlsConnector>,
+    base:      String,
+    // the name ureq hands the connector for `base`
+    host:      String,
+}
+
+impl Session {
+    pub fn new(connector: MbedTlsConnector, authority: &str) -> Session {
+        let connector = Arc::new(connector);
+        let agent = ureq::builder()
+            .tls_connector(connector.clone())
+            .timeout_connect(CONNECT_TIMEOUT)
+            .timeout(IO_TIMEOUT)
+            .max_idle_connections_per_host(1)
+            .build();
+        let base = format!("https://{}", authority);
+        let host = url::Url::parse(&base).ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        Session {
+            agent:     agent,
+            connector: connector,
+            base:      base,
+            host:      host,
+        }
+    }
+
+    /// TLS to `addr` ahead of the first request, and the certificates the
+    /// registrar presented, end-entity first
+    pub fn handshake(&self, addr: SocketAddr) -> io::Result<Vec<Vec<u8>>> {
+        let conn = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        conn.set_read_timeout(Some(IO_TIMEOUT))?;
+        conn.set_write_timeout(Some(IO_TIMEOUT))?;
+        self.connector.preconnect(&self.host, Box::new(conn))
+    }
+
+    /// the registrar chain every connection of this session has presented
+    pub fn peer_chain(&self) -> Vec<Vec<u8>> {
+        self.connector.peer_chain()
+    }
+
+    /// an HTTP error status is a response like any other, only transport
+    /// failures are errors
+    pub fn request(&self,
+                   method:  &str,
+                   path:    &str,
+                   headers: &[(&str, &str)],
+                   body:    &[u8]) -> io::Result<HttpResponse> {
+        let mut req = self.agent.request(method, &format!("{}{}", self.base, path));
+        for (name, value) in headers {
+            req = req.set(name, value);
+        }
+        let sent = if method == "GET" && body.is_empty() {
+            req.call()
+        } else {
+            req.send_bytes(body)
+        };
+        match sent {
+            Ok(resp) => HttpResponse::read(resp),
+            Err(ureq::Error::Status(_, resp)) => HttpResponse::read(resp),
+            Err(error) => Err(io::Error::new(io::ErrorKind::Other, error.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn response_headers() {
+        let resp = ureq::Response::new(406, "Not Acceptable", "no cbor here").unwrap();
+        let resp = HttpResponse::read(resp).unwrap();
+        assert_eq!(resp.status, 406);
+        assert!(!resp.is_success());
+        assert_eq!(resp.body, b"no cbor here".to_vec());
+    }
+
+    #[test]
+    fn oversized_response() {
+        let body = "x".repeat(MAX_BODY as usize);
+        let resp = ureq::Response::new(200, "OK", &body).unwrap();
+        assert_eq!(HttpResponse::read(resp).unwrap().body.len() as u64, MAX_BODY);
+
+        let body = "x".repeat(MAX_BODY as usize + 1);
+        let resp = ureq::Response::new(200, "OK", &body).unwrap();
+        let error = HttpResponse::read(resp).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
+
+/*
+ * Local Variables:
+ * mode: rust
+ * compile-command: "cd .. && cargo build"
+ * End:
+ */
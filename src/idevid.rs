@@ -0,0 +1,294 @@
+/*
+ * Copyright [2021] <mcr@sandelman.ca>
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+ *
+ */
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use mbedtls::pk::Pk;
+
+use crate::args::BootstrapOptions;
+use crate::asn1::{self, Asn1Error};
+use crate::custom_voucher::{self, VoucherAlgorithm};
+use crate::ed25519;
+use crate::utils;
+
+/*
+ * The manufacturer installed identity of this device.
+ * The serial-number that goes into the voucher-request is the
+ * X520SerialNumber from the subject of the IDevID certificate.
+ * The private key is kept in PEM form, which is what the `Sign` trait wants;
+ * Ed25519 keys are PKCS#8 PEM, which only our own code reads.
+ * Any intermediate certificates follow the IDevID in the --idevid-cert
+ * file, they are sent along in the TLS handshake.
+ */
+pub struct IDevID {
+    pub cert_der:      Vec<u8>,
+    /// the IDevID certificate, then the intermediates
+    pub chain:         Vec<Vec<u8>>,
+    pub serial_number: Vec<u8>,
+    pub key_pem:       Vec<u8>,
+    pub sig_alg:       VoucherAlgorithm,
+}
+
+#[derive(Debug)]
+pub enum IDevIDError {
+    NoCertificateConfigured,
+    NoPrivateKeyConfigured,
+    ReadFailed(std::io::Error),
+    BadPem,
+    BadPrivateKey(mbedtls::Error),
+    BadEncoding(Asn1Error),
+    NoSerialNumber,
+    KeyMismatch,
+    UnsupportedKey,
+    NoTlsKey,
+}
+
+impl fmt::Display for IDevIDError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IDevIDError::NoCertificateConfigured => {
+                write!(f, "no IDevID certificate configured, use --idevid-cert")
+            },
+            IDevIDError::NoPrivateKeyConfigured => {
+                write!(f, "no IDevID private key configured, use --idevid-priv")
+            },
+            IDevIDError::ReadFailed(error) => {
+                write!(f, "can not read IDevID: {}", error)
+            },
+            IDevIDError::BadPem => {
+                write!(f, "IDevID PEM file has no valid PEM block")
+            },
+            IDevIDError::BadPrivateKey(error) => {
+                write!(f, "IDevID private key not parsed: {}", error)
+            },
+            IDevIDError::BadEncoding(error) => {
+                write!(f, "IDevID certificate malformed: {}", error)
+            },
+            IDevIDError::NoSerialNumber => {
+                write!(f, "IDevID certificate subject has no serialNumber attribute")
+            },
+            IDevIDError::KeyMismatch => {
+                write!(f, "IDevID private key does not match the IDevID certificate")
+            },
+            IDevIDError::UnsupportedKey => {
+                write!(f, "IDevID private key is not RSA, Ed25519, or on P-256, P-384 or P-521")
+            },
+            IDevIDError::NoTlsKey => {
+                write!(f, "IDevID key is Ed25519, which mbedtls can not use for the TLS client certificate")
+            }
+        }
+    }
+}
+
+impl From<Asn1Error> for IDevIDError {
+    fn from(kind: Asn1Error) -> Self {
+        Self::BadEncoding(kind)
+    }
+}
+
+impl IDevID {
+    pub fn load(options: &BootstrapOptions) -> Result<IDevID, IDevIDError> {
+        let cert_path = options.idevid_cert.as_ref().ok_or(IDevIDError::NoCertificateConfigured)?;
+        let priv_path = options.idevid_priv.as_ref().ok_or(IDevIDError::NoPrivateKeyConfigured)?;
+        IDevID::from_parts(&read_file(cert_path)?, &read_file(priv_path)?)
+    }
+
+    /// accepts either PEM or DER encoded certificate and private key,
+    /// and insists that they belong together.
+    pub fn from_parts(raw_cert: &[u8], raw_key: &[u8]) -> Result<IDevID, IDevIDError> {
+        let chain = certificate_chain(raw_cert)?;
+        let cert_der = chain[0].clone();
+        let serial_number = serial_number_from(&cert_der)?;
+
+        let spki = asn1::parse_certificate(&cert_der)?.spki.raw.to_vec();
+        let key_der = if is_pem(raw_key) {
+            utils::pem_to_der(raw_key).ok_or(IDevIDError::BadPem)?
+        } else {
+            raw_key.to_vec()
+        };
+
+        let (key_pem, sig_alg) = if let Some(seed) = ed25519::private_key_from_pkcs8(&key_der) {
+            // mbedtls has no Ed25519, so the key is checked here
+            let public = ed25519::public_key_from_seed(&seed).ok_or(IDevIDError::UnsupportedKey)?;
+            if ed25519::public_key_from_spki(&spki) != Some(public) {
+                return Err(IDevIDError::KeyMismatch);
+            }
+            (utils::der_to_pem("PRIVATE KEY", &key_der).into_bytes(), VoucherAlgorithm::EdDSA)
+        } else {
+            let mut pk = Pk::from_private_key(&key_der, None).map_err(IDevIDError::BadPrivateKey)?;
+            if pk.write_public_der_vec().map_err(IDevIDError::BadPrivateKey)? != spki {
+                return Err(IDevIDError::KeyMismatch);
+            }
+            let sig_alg = custom_voucher::signature_algorithm_for(&pk)
+                .ok_or(IDevIDError::UnsupportedKey)?;
+            (pk.write_private_pem_string().map_err(IDevIDError::BadPrivateKey)?.into_bytes(), sig_alg)
+        };
+
+        Ok(IDevID {
+            cert_der:      cert_der,
+            chain:         chain,
+            serial_number: serial_number,
+            key_pem:       key_pem,
+            sig_alg:       sig_alg,
+        })
+    }
+
+    /// serverkeygen keys come encrypted to the IDevID, and only RSA
+    /// (KeyTrans) recipients are supported
+    pub fn can_receive_server_key(&self) -> bool {
+        self.sig_alg == VoucherAlgorithm::PS256
+    }
+
+    /// the private key for the TLS client certificate; mbedtls has no Ed25519
+    pub fn tls_key(&self) -> Result<Pk, IDevIDError> {
+        if self.sig_alg == VoucherAlgorithm::EdDSA {
+            return Err(IDevIDError::NoTlsKey);
+        }
+        Pk::from_private_key(&utils::null_terminate_bytes!(self.key_pem), None)
+            .map_err(IDevIDError::BadPrivateKey)
+    }
+}
+
+fn is_pem(raw: &[u8]) -> bool {
+    raw.starts_with(b"-----BEGIN")
+}
+
+/// mbedtls can not parse certificates with Ed25519 keys, so
+/// this only checks that the structure is there
+fn certificate_chain(raw: &[u8]) -> Result<Vec<Vec<u8>>, IDevIDError> {
+    let chain = if is_pem(raw) {
+        utils::pem_blocks(raw)
+    } else {
+        vec![raw.to_vec()]
+    };
+    if chain.is_empty() {
+        return Err(IDevIDError::BadPem);
+    }
+    for der in &chain {
+        asn1::parse_certificate(der)?;
+    }
+    Ok(chain)
+}
+
+/// pull the X520SerialNumber out of the subject of a DER certificate
+pub fn serial_number_from(cert_der: &[u8]) -> Result<Vec<u8>, IDevIDError> {
+    let parts = asn1::parse_certificate(cert_der)?;
+    match asn1::find_name_attribute(&parts.subject, asn1::OID_SERIAL_NUMBER)? {
+        Some(sn) if !sn.value.is_empty() => Ok(sn.value.to_vec()),
+        _ => Err(IDevIDError::NoSerialNumber),
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, IDevIDError> {
+    fs::read(path).map_err(IDevIDError::ReadFailed)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use mbedtls::pk::EcGroupId;
+    use crate::asn1::{der_constructed, der_tlv};
+    use crate::support_rand;
+    use crate::test_fixtures::{cert_for, ed25519_cert, ed25519_cert_for, ed25519_key_pem};
+
+    static SERIAL: &[u8] = b"00-D0-E5-F2-00-02";
+
+    fn pledge_name() -> Vec<u8> {
+        der_constructed(asn1::TAG_SEQUENCE, &[
+            &der_constructed(asn1::TAG_SET, &[&der_constructed(asn1::TAG_SEQUENCE, &[
+                &der_tlv(asn1::TAG_OID, asn1::OID_SERIAL_NUMBER),
+                &der_tlv(asn1::TAG_PRINTABLE_STRING, SERIAL),
+            ])]),
+        ])
+    }
+
+    fn pledge_cert() -> Vec<u8> {
+        ed25519_cert_for(&pledge_name())
+    }
+
+    /// a fresh key on `curve`, and a certificate for it
+    fn ec_pledge(curve: EcGroupId) -> (Vec<u8>, Pk) {
+        let mut key = Pk::generate_ec(&mut support_rand::signing_rng().unwrap(), curve).unwrap();
+        let cert = cert_for(&pledge_name(), &key.write_public_der_vec().unwrap());
+        (cert, key)
+    }
+
+    #[test]
+    fn ecdsa_keys() {
+        for (curve, alg) in vec![(EcGroupId::SecP256R1, VoucherAlgorithm::ES256),
+                                 (EcGroupId::SecP384R1, VoucherAlgorithm::ES384)] {
+            let (cert, mut key) = ec_pledge(curve);
+            let key_pem = key.write_private_pem_string().unwrap();
+            let idevid = IDevID::from_parts(&cert, key_pem.as_bytes()).unwrap();
+            assert_eq!(idevid.sig_alg, alg);
+            assert_eq!(idevid.serial_number, SERIAL.to_vec());
+            assert!(idevid.tls_key().is_ok());
+
+            let key_der = key.write_private_der_vec().unwrap();
+            assert_eq!(IDevID::from_parts(&cert, &key_der).unwrap().sig_alg, alg);
+
+            // another key on the same curve
+            let (_, mut other) = ec_pledge(curve);
+            let other_der = other.write_private_der_vec().unwrap();
+            assert!(matches!(IDevID::from_parts(&cert, &other_der), Err(IDevIDError::KeyMismatch)));
+        }
+
+        // a P-384 key for a P-256 certificate
+        let (cert, _) = ec_pledge(EcGroupId::SecP256R1);
+        let (_, mut other) = ec_pledge(EcGroupId::SecP384R1);
+        let other_der = other.write_private_der_vec().unwrap();
+        assert!(matches!(IDevID::from_parts(&cert, &other_der), Err(IDevIDError::KeyMismatch)));
+    }
+
+    #[test]
+    fn pem_and_der() {
+        let cert_pem = utils::der_to_pem("CERTIFICATE", &pledge_cert());
+        let idevid = IDevID::from_parts(cert_pem.as_bytes(), &ed25519_key_pem()).unwrap();
+        assert_eq!(idevid.serial_number, SERIAL.to_vec());
+        assert_eq!(idevid.chain, vec![pledge_cert()]);
+        assert_eq!(idevid.sig_alg, VoucherAlgorithm::EdDSA);
+        assert!(matches!(idevid.tls_key(), Err(IDevIDError::NoTlsKey)));
+
+        let key_der = utils::pem_to_der(&ed25519_key_pem()).unwrap();
+        let idevid = IDevID::from_parts(&pledge_cert(), &key_der).unwrap();
+        assert_eq!(idevid.cert_der, pledge_cert());
+        assert_eq!(idevid.key_pem, ed25519_key_pem());
+    }
+
+    #[test]
+    fn key_does_not_match() {
+        let mut key_der = utils::pem_to_der(&ed25519_key_pem()).unwrap();
+        *key_der.last_mut().unwrap() ^= 0x01;
+        assert!(matches!(IDevID::from_parts(&pledge_cert(), &key_der), Err(IDevIDError::KeyMismatch)));
+    }
+
+    #[test]
+    fn no_serial_number() {
+        assert!(matches!(serial_number_from(&ed25519_cert()), Err(IDevIDError::NoSerialNumber)));
+        assert!(matches!(IDevID::from_parts(&ed25519_cert(), &ed25519_key_pem()),
+                         Err(IDevIDError::NoSerialNumber)));
+    }
+}
+
+/*
+ * Local Variables:
+ * mode: rust
+ * compile-command: "cd .. && cargo build"
+ * End:
+ */
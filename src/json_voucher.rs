@@ -0,0 +1,310 @@
+/*
+ * Copyright [2021] <mcr@sandelman.ca>
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+ *
+ */
+
+/*
+ * RFC8366 section 5.3 JSON vouchers, and the RFC8995 section 3 JSON
+ * voucher-requests, to and from the minerva_voucher attributes.  Binary
+ * leaves are base64, the dates are RFC3339 date-and-time strings.
+ */
+
+use serde_json::{Map, Value};
+use minerva_voucher::{Voucher, VoucherError, attr::*};
+
+pub const VOUCHER_CONTAINER: &str = "ietf-voucher:voucher";
+pub const VOUCHER_REQUEST_CONTAINER: &str = "ietf-voucher-request:voucher";
+
+// every attribute, in the order they are written out
+static ALL_ATTRS: [AttrDisc; 15] = [
+    ATTR_ASSERTION,
+    ATTR_CREATED_ON,
+    ATTR_DOMAIN_CERT_REVOCATION_CHECKS,
+    ATTR_EXPIRES_ON,
+    ATTR_IDEVID_ISSUER,
+    ATTR_LAST_RENEWAL_DATE,
+    ATTR_NONCE,
+    ATTR_PINNED_DOMAIN_CERT,
+    ATTR_PINNED_DOMAIN_PUBK,
+    ATTR_PINNED_DOMAIN_PUBK_SHA256,
+    ATTR_PRIOR_SIGNED_VOUCHER_REQUEST,
+    ATTR_PROXIMITY_REGISTRAR_CERT,
+    ATTR_PROXIMITY_REGISTRAR_PUBK,
+    ATTR_PROXIMITY_REGISTRAR_PUBK_SHA256,
+    ATTR_SERIAL_NUMBER,
+];
+
+fn binary(b: &[u8]) -> Value {
+    Value::String(base64::encode(b))
+}
+
+fn date(t: u64) -> Value {
+    Value::String(format_date_time(t))
+}
+
+fn attr_to_json(attr: &Attr) -> (&'static str, Value) {
+    match attr {
+        Attr::Assertion(a) => ("assertion", Value::String(match a {
+            Assertion::Verified  => "verified",
+            Assertion::Logged    => "logged",
+            Assertion::Proximity => "proximity",
+        }.to_string())),
+        Attr::CreatedOn(t)                    => ("created-on", date(*t)),
+        Attr::DomainCertRevocationChecks(b)   => ("domain-cert-revocation-checks", Value::Bool(*b)),
+        Attr::ExpiresOn(t)                    => ("expires-on", date(*t)),
+        Attr::IdevidIssuer(b)                 => ("idevid-issuer", binary(b)),
+        Attr::LastRenewalDate(t)              => ("last-renewal-date", date(*t)),
+        Attr::Nonce(b)                        => ("nonce", binary(b)),
+        Attr::PinnedDomainCert(b)             => ("pinned-domain-cert", binary(b)),
+        Attr::PinnedDomainPubk(b)             => ("pinned-domain-pubk", binary(b)),
+        Attr::PinnedDomainPubkSha256(b)       => ("pinned-domain-pubk-sha256", binary(b)),
+        Attr::PriorSignedVoucherRequest(b)    => ("prior-signed-voucher-request", binary(b)),
+        Attr::ProximityRegistrarCert(b)       => ("proximity-registrar-cert", binary(b)),
+        Attr::ProximityRegistrarPubk(b)       => ("proximity-registrar-pubk", binary(b)),
+        Attr::ProximityRegistrarPubkSha256(b) => ("proximity-registrar-pubk-sha256", binary(b)),
+        Attr::SerialNumber(b)                 => ("serial-number", Value::String(String::from_utf8_lossy(b).into_owned())),
+    }
+}
+
+/// unknown leaves are skipped, as RFC8366 section 5.2 allows for extensions
+fn attr_from_json(name: &str, value: &Value) -> Result<Option<Attr>, VoucherError> {
+    let bad = || VoucherError::MalformedInput;
+    let as_binary = || value.as_str()
+        .and_then(|s| base64::decode(s).ok())
+        .ok_or_else(bad);
+    let as_date = || value.as_str()
+        .and_then(parse_date_time)
+        .ok_or_else(bad);
+
+    let attr = match name {
+        "assertion" => Attr::Assertion(match value.as_str() {
+            Some("verified")  => Assertion::Verified,
+            Some("logged")    => Assertion::Logged,
+            Some("proximity") => Assertion::Proximity,
+            _ => return Err(bad()),
+        }),
+        "created-on"                      => Attr::CreatedOn(as_date()?),
+        "domain-cert-revocation-checks"   => Attr::DomainCertRevocationChecks(value.as_bool().ok_or_else(bad)?),
+        "expires-on"                      => Attr::ExpiresOn(as_date()?),
+        "idevid-issuer"                   => Attr::IdevidIssuer(as_binary()?),
+        "last-renewal-date"               => Attr::LastRenewalDate(as_date()?),
+        "nonce"                           => Attr::Nonce(as_binary()?),
+        "pinned-domain-cert"              => Attr::PinnedDomainCert(as_binary()?),
+        "pinned-domain-pubk"              => Attr::PinnedDomainPubk(as_binary()?),
+        "pinned-domain-pubk-sha256"       => Attr::PinnedDomainPubkSha256(as_binary()?),
+        "prior-signed-voucher-request"    => Attr::PriorSignedVoucherRequest(as_binary()?),
+        "proximity-registrar-cert"        => Attr::ProximityRegistrarCert(as_binary()?),
+        "proximity-registrar-pubk"        => Attr::ProximityRegistrarPubk(as_binary()?),
+        "proximity-registrar-pubk-sha256" => Attr::ProximityRegistrarPubkSha256(as_binary()?),
+        "serial-number" => Attr::SerialNumber(value.as_str().ok_or_else(bad)?.as_bytes().to_vec()),
+        _ => return Ok(None),
+    };
+    Ok(Some(attr))
+}
+
+pub fn to_json(voucher: &Voucher) -> Vec<u8> {
+    let mut leaves = Map::new();
+    for disc in ALL_ATTRS.iter() {
+        if let Some(attr) = voucher.get(*disc) {
+            let (name, value) = attr_to_json(attr);
+            leaves.insert(name.to_string(), value);
+        }
+    }
+
+    let container = if voucher.is_vrq() { VOUCHER_REQUEST_CONTAINER } else { VOUCHER_CONTAINER };
+    let mut top = Map::new();
+    top.insert(container.to_string(), Value::Object(leaves));
+    serde_json::to_vec(&Value::Object(top)).unwrap()
+}
+
+pub fn from_json(raw: &[u8]) -> Result<Voucher, VoucherError> {
+    let top: Value = serde_json::from_slice(raw).or(Err(VoucherError::MalformedInput))?;
+
+    let (mut voucher, leaves) = if let Some(leaves) = top.get(VOUCHER_CONTAINER) {
+        (Voucher::new_vch(), leaves)
+    } else if let Some(leaves) = top.get(VOUCHER_REQUEST_CONTAINER) {
+        (Voucher::new_vrq(), leaves)
+    } else {
+        return Err(VoucherError::MalformedInput);
+    };
+
+    let leaves = leaves.as_object().ok_or(VoucherError::MalformedInput)?;
+    for (name, value) in leaves {
+        if let Some(attr) = attr_from_json(name, value)? {
+            voucher.set(attr);
+        }
+    }
+    Ok(voucher)
+}
+
+/*
+ * days since 1970-01-01 in the proleptic Gregorian calendar, and back,
+ * after http://howardhinnant.github.io/date_algorithms.html
+ */
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    (yoe + era * 400 + (m <= 2) as i64, m, d)
+}
+
+pub fn format_date_time(t: u64) -> String {
+    let secs = t as i64;
+    let (y, m, d) = civil_from_days(secs.div_euclid(86400));
+    let tod = secs.rem_euclid(86400);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", y, m, d, tod / 3600, tod / 60 % 60, tod % 60)
+}
+
+/// RFC3339 date-time, any fraction is dropped
+pub fn parse_date_time(s: &str) -> Option<u64> {
+    let b = s.as_bytes();
+    if b.len() < 20 || b[4] != b'-' || b[7] != b'-' || !(b[10] == b'T' || b[10] == b't')
+        || b[13] != b':' || b[16] != b':' {
+        return None;
+    }
+    let num = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = s.get(range)?;
+        if !digits.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    };
+
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, min, sec) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60 {
+        return None;
+    }
+
+    let mut rest = &s[19..];
+    if rest.starts_with('.') {
+        let digits = rest[1..].bytes().take_while(|c| c.is_ascii_digit()).count();
+        if digits == 0 {
+            return None;
+        }
+        rest = &rest[1 + digits..];
+    }
+
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && (rest.starts_with('+') || rest.starts_with('-')) && rest.as_bytes()[3] == b':' => {
+            let (oh, om) = (num(s.len() - 5..s.len() - 3)?, num(s.len() - 2..s.len())?);
+            let offset = oh * 3600 + om * 60;
+            if rest.starts_with('-') { -offset } else { offset }
+        },
+        _ => return None,
+    };
+
+    let t = days_from_civil(year, month as u32, day as u32) * 86400
+        + hour * 3600 + min * 60 + sec - offset;
+    if t < 0 { None } else { Some(t as u64) }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn date_time() {
+        assert_eq!(parse_date_time("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_date_time("2020-09-02T22:33:54Z"), Some(1599086034));
+        assert_eq!(parse_date_time("2020-09-03T00:33:54.123+02:00"), Some(1599086034));
+        assert_eq!(parse_date_time("2020-09-02T17:33:54-05:00"), Some(1599086034));
+        assert_eq!(format_date_time(1599086034), "2020-09-02T22:33:54Z");
+        assert_eq!(format_date_time(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(parse_date_time("2000-02-29T00:00:00Z"), Some(951782400));
+
+        assert_eq!(parse_date_time("2020-09-02 22:33:54Z"), None);
+        assert_eq!(parse_date_time("2020-13-02T22:33:54Z"), None);
+        assert_eq!(parse_date_time("2020-09-02T22:33:54"), None);
+        // byte 3 of the offset is inside a multibyte character
+        assert_eq!(parse_date_time("2020-09-02T22:33:54+0é00"), None);
+    }
+
+    #[test]
+    fn rfc8366_example() {
+        let raw = br#"{
+          "ietf-voucher:voucher": {
+            "created-on": "2016-10-07T19:31:42Z",
+            "expires-on": "2016-10-21T19:31:42Z",
+            "assertion": "verified",
+            "serial-number": "JADA123456789",
+            "idevid-issuer": "base64encodedvalue==",
+            "pinned-domain-cert": "base64encodedvalue==",
+            "domain-cert-revocation-checks": true,
+            "last-renewal-date": "2017-10-07T19:31:42Z",
+            "ietf-voucher:unknown-extension": 1
+          }
+        }"#;
+        let vch = from_json(raw).unwrap();
+        assert!(!vch.is_vrq());
+        assert_eq!(vch.get(ATTR_SERIAL_NUMBER), Some(&Attr::SerialNumber(b"JADA123456789".to_vec())));
+        assert_eq!(vch.get(ATTR_ASSERTION), Some(&Attr::Assertion(Assertion::Verified)));
+        assert_eq!(vch.get(ATTR_EXPIRES_ON), Some(&Attr::ExpiresOn(1477078302)));
+        assert_eq!(vch.get(ATTR_DOMAIN_CERT_REVOCATION_CHECKS), Some(&Attr::DomainCertRevocationChecks(true)));
+        assert!(vch.get(ATTR_NONCE).is_none());
+    }
+
+    #[test]
+    fn voucher_request_round_trip() {
+        let mut vrq = Voucher::new_vrq();
+        vrq.set(Attr::Assertion(Assertion::Proximity))
+            .set(Attr::Nonce(vec![1, 2, 3, 4]))
+            .set(Attr::CreatedOn(1599086034))
+            .set(Attr::SerialNumber(b"00-D0-E5-F2-00-02".to_vec()))
+            .set(Attr::ProximityRegistrarCert(vec![0x30, 0x00]));
+
+        let raw = to_json(&vrq);
+        let text = String::from_utf8(raw.clone()).unwrap();
+        assert!(text.starts_with("{\"ietf-voucher-request:voucher\":{\"assertion\":\"proximity\""));
+        assert!(text.contains("\"created-on\":\"2020-09-02T22:33:54Z\""));
+        assert!(text.contains("\"nonce\":\"AQIDBA==\""));
+
+        let decoded = from_json(&raw).unwrap();
+        assert!(decoded.is_vrq());
+        for disc in ALL_ATTRS.iter() {
+            assert_eq!(decoded.get(*disc), vrq.get(*disc));
+        }
+    }
+
+    #[test]
+    fn malformed_json() {
+        assert!(from_json(b"{\"ietf-voucher:voucher\": {\"nonce\": 5}}").is_err());
+        assert!(from_json(b"{\"ietf-voucher:voucher\": {\"assertion\": \"maybe\"}}").is_err());
+        assert!(from_json(b"{\"voucher\": {}}").is_err());
+        assert!(from_json(b"\xd2\x84").is_err());
+    }
+}
+/*
+ * Local Variables:
+ * mode: rust
+ * compile-command: "cd .. && cargo build"
+ * End:
+ */
@@ -0,0 +1,264 @@
+/*
+ * Copyright [2021] <mcr@sandelman.ca>
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+ *
+ */
+
+/*
+ * JWS signed JSON vouchers (draft-ietf-anima-jws-voucher): the RFC8366
+ * JSON as the payload of a JWS (RFC7515) in the general JSON
+ * serialization, with the signer certificate and its chain in "x5c".
+ * The flattened serialization is accepted as well.
+ */
+
+use serde_json::{json, Value};
+
+use crate::custom_voucher::{self, VoucherAlgorithm, VoucherError};
+use crate::utils;
+use crate::voucher_validation;
+
+pub const JWS_VOUCHER_TYPE: &str = "voucher-jws+json";
+
+pub struct JwsSignature {
+    /// the base64url protected header, as it was signed
+    pub protected: String,
+    pub alg:       Option<VoucherAlgorithm>,
+    /// the signer certificate first, DER
+    pub x5c:       Vec<Vec<u8>>,
+    pub signature: Vec<u8>,
+}
+
+pub struct Jws {
+    payload_b64:    String,
+    pub payload:    Vec<u8>,
+    pub signatures: Vec<JwsSignature>,
+}
+
+pub fn alg_name(alg: VoucherAlgorithm) -> &'static str {
+    match alg {
+        VoucherAlgorithm::ES256 => "ES256",
+        VoucherAlgorithm::ES384 => "ES384",
+        VoucherAlgorithm::ES512 => "ES512",
+        VoucherAlgorithm::PS256 => "PS256",
+        VoucherAlgorithm::EdDSA => "EdDSA",
+    }
+}
+
+pub fn alg_from_name(name: &str) -> Option<VoucherAlgorithm> {
+    match name {
+        "ES256" => Some(VoucherAlgorithm::ES256),
+        "ES384" => Some(VoucherAlgorithm::ES384),
+        "ES512" => Some(VoucherAlgorithm::ES512),
+        "PS256" => Some(VoucherAlgorithm::PS256),
+        "EdDSA" => Some(VoucherAlgorithm::EdDSA),
+        _ => None,
+    }
+}
+
+fn b64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn from_b64url(text: &str) -> Option<Vec<u8>> {
+    base64::decode_config(text, base64::URL_SAFE_NO_PAD).ok()
+}
+
+fn signing_input(protected: &str, payload_b64: &str) -> Vec<u8> {
+    format!("{}.{}", protected, payload_b64).into_bytes()
+}
+
+impl JwsSignature {
+    fn decode(entry: &Value) -> Result<JwsSignature, VoucherError> {
+        let bad = || VoucherError::MalformedInput;
+        let protected = entry.get("protected").and_then(Value::as_str).ok_or_else(bad)?;
+        let signature = entry.get("signature").and_then(Value::as_str)
+            .and_then(from_b64url).ok_or_else(bad)?;
+
+        let header: Value = from_b64url(protected)
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(bad)?;
+        if !header.is_object() {
+            return Err(bad());
+        }
+
+        // x5c is only believed from the protected header, and it is
+        // standard base64 (RFC7515 section 4.1.6)
+        let mut x5c = Vec::new();
+        if let Some(certs) = header.get("x5c") {
+            for cert in certs.as_array().ok_or_else(bad)? {
+                x5c.push(cert.as_str().and_then(|c| base64::decode(c).ok()).ok_or_else(bad)?);
+            }
+        }
+
+        // nothing here understands any critical extension
+        let alg = match header.get("crit") {
+            Some(_) => None,
+            None => header.get("alg").and_then(Value::as_str).and_then(alg_from_name),
+        };
+
+        Ok(JwsSignature {
+            protected: protected.to_string(),
+            alg:       alg,
+            x5c:       x5c,
+            signature: signature,
+        })
+    }
+}
+
+impl Jws {
+    pub fn decode(raw: &[u8]) -> Result<Jws, VoucherError> {
+        let bad = || VoucherError::MalformedInput;
+        let top: Value = serde_json::from_slice(raw).or(Err(bad()))?;
+
+        let payload_b64 = top.get("payload").and_then(Value::as_str).ok_or_else(bad)?;
+        let payload = from_b64url(payload_b64).ok_or_else(bad)?;
+
+        let signatures = match top.get("signatures") {
+            Some(entries) => entries.as_array().ok_or_else(bad)?
+                .iter().map(JwsSignature::decode).collect::<Result<Vec<_>, _>>()?,
+            None => vec![JwsSignature::decode(&top)?],
+        };
+
+        Ok(Jws {
+            payload_b64: payload_b64.to_string(),
+            payload:     payload,
+            signatures:  signatures,
+        })
+    }
+
+    /*
+     * the anchor (PEM or DER) may be the signing certificate itself, or a
+     * CA that the first x5c certificate chains to through the rest of x5c.
+     */
+    pub fn verify(&self, anchor: &[u8]) -> bool {
+        let anchor = match utils::cert_der(anchor) {
+            Some(anchor) => anchor,
+            None => return false,
+        };
+        self.signatures.iter().any(|sig| self.verify_signature(sig, &anchor))
+    }
+
+    fn verify_signature(&self, sig: &JwsSignature, anchor: &[u8]) -> bool {
+        let alg = match sig.alg {
+            Some(alg) => alg,
+            None => return false,
+        };
+
+        let signer = match sig.x5c.first() {
+            Some(signer) if signer.as_slice() != anchor => {
+                // mbedtls can not verify a chain of Ed25519 certificates
+                if alg == VoucherAlgorithm::EdDSA {
+                    return false;
+                }
+                match voucher_validation::chain_verifies(&sig.x5c, anchor) {
+                    Ok(true) => signer.as_slice(),
+                    _ => return false,
+                }
+            },
+            _ => anchor,
+        };
+
+        let input = signing_input(&sig.protected, &self.payload_b64);
+        custom_voucher::verify_detached(signer, alg, &input, &sig.signature)
+    }
+}
+
+/// the general JSON serialization, with one signature by the first of `chain`
+pub fn sign(payload: &[u8],
+            chain:   &[Vec<u8>],
+            key_pem: &[u8],
+            alg:     VoucherAlgorithm) -> Result<Vec<u8>, VoucherError> {
+    let x5c: Vec<String> = chain.iter().map(base64::encode).collect();
+    let header = json!({
+        "alg": alg_name(alg),
+        "typ": JWS_VOUCHER_TYPE,
+        "x5c": x5c,
+    });
+    let protected = b64url(&serde_json::to_vec(&header).unwrap());
+    let payload_b64 = b64url(payload);
+
+    let signature = custom_voucher::sign_detached(key_pem, alg, &signing_input(&protected, &payload_b64))?;
+
+    let jws = json!({
+        "payload": payload_b64,
+        "signatures": [{
+            "protected": protected,
+            "signature": b64url(&signature),
+        }],
+    });
+    Ok(serde_json::to_vec(&jws).unwrap())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::test_fixtures::{ed25519_cert, ed25519_key_pem};
+
+    static PAYLOAD: &[u8] = br#"{"ietf-voucher-request:voucher":{"assertion":"proximity"}}"#;
+
+    #[test]
+    fn sign_and_verify() {
+        let cert = ed25519_cert();
+        let raw = sign(PAYLOAD, &[cert.clone()], &ed25519_key_pem(), VoucherAlgorithm::EdDSA).unwrap();
+
+        let jws = Jws::decode(&raw).unwrap();
+        assert_eq!(jws.payload, PAYLOAD.to_vec());
+        assert_eq!(jws.signatures.len(), 1);
+        assert_eq!(jws.signatures[0].alg, Some(VoucherAlgorithm::EdDSA));
+        assert_eq!(jws.signatures[0].x5c, vec![cert.clone()]);
+        assert!(jws.verify(&cert));
+        assert!(jws.verify(utils::der_to_pem("CERTIFICATE", &cert).as_bytes()));
+
+        // an Ed25519 signer that is not the anchor can not be chained
+        assert!(!jws.verify(&[0x30, 0x00]));
+    }
+
+    #[test]
+    fn flattened_and_tampered() {
+        let cert = ed25519_cert();
+        let raw = sign(PAYLOAD, &[cert.clone()], &ed25519_key_pem(), VoucherAlgorithm::EdDSA).unwrap();
+        let general: Value = serde_json::from_slice(&raw).unwrap();
+
+        let mut flattened = general["signatures"][0].clone();
+        flattened["payload"] = general["payload"].clone();
+        let jws = Jws::decode(&serde_json::to_vec(&flattened).unwrap()).unwrap();
+        assert!(jws.verify(&cert));
+
+        flattened["payload"] = Value::String(b64url(b"{}"));
+        let jws = Jws::decode(&serde_json::to_vec(&flattened).unwrap()).unwrap();
+        assert!(!jws.verify(&cert));
+    }
+
+    #[test]
+    fn unusable_headers() {
+        let protected = b64url(br#"{"alg":"EdDSA","crit":["b64"],"b64":false}"#);
+        let raw = json!({ "payload": "e30", "protected": protected, "signature": "AA" });
+        let jws = Jws::decode(&serde_json::to_vec(&raw).unwrap()).unwrap();
+        assert_eq!(jws.signatures[0].alg, None);
+
+        let protected = b64url(br#"{"alg":"none"}"#);
+        let raw = json!({ "payload": "e30", "protected": protected, "signature": "" });
+        let jws = Jws::decode(&serde_json::to_vec(&raw).unwrap()).unwrap();
+        assert!(!jws.verify(&ed25519_cert()));
+
+        assert!(Jws::decode(b"{\"payload\": \"e30\"}").is_err());
+        assert!(Jws::decode(b"{\"payload\": \"!!\", \"protected\": \"e30\", \"signature\": \"\"}").is_err());
+    }
+}
+/*
+ * Local Variables:
+ * mode: rust
+ * compile-command: "cd .. && cargo build"
+ * End:
+ */
@@ -21,10 +21,26 @@ use structopt::StructOpt;
 pub mod args;
 pub mod bootstrap;
 pub mod mbedtls_connector;
+pub mod idevid;
+mod asn1;
+mod cbor;
+mod cms;
+mod cose;
+mod csr;
+mod ed25519;
+mod est;
+mod https;
+mod json_voucher;
+mod jws;
+mod renew;
+mod voucher_validation;
 mod support_rand;
+mod telemetry;
+#[cfg(test)]
+mod test_fixtures;
 mod custom_voucher;
 mod utils;
-use bootstrap::BootstrapState;
+use bootstrap::{BootstrapState, Pledge};
 
 static VERSION: &str = "0.9.0";
 //static DEFAULT_JOIN_THREADS: u16 = 16;
@@ -46,15 +62,20 @@ static VERSION: &str = "0.9.0";
  * 4. If a thread takes too long, or fails, then the interface is put back on
  *    the queue to be dealt with soon.
  *
+ * 5. With --renew, once it has an LDevID it stays around and renews it,
+ *    going back to 2. if the domain refuses.
+ *
  */
 
-fn bootstrap(args: args::BootstrapOptions) -> Result<(), String> {
+fn bootstrap(args: &args::BootstrapOptions) -> Result<(), String> {
     let (sender, receiver) = BootstrapState::channel();
     let mut state = BootstrapState::empty(sender);
 
+    let pledge = Pledge::from_options(args)?;
+
     //init_psa_crypto();
 
-    if let Some(url) = args.registrar {
+    if let Some(url) = &args.registrar {
         state.add_registrar_by_url(url.clone()).unwrap();
     } else {
         // start loop looking for interfaces,
@@ -66,7 +87,11 @@ fn bootstrap(args: args::BootstrapOptions) -> Result<(), String> {
     //rt.spawn(async move {   // receiver moved
     println!("Looking for Registrars using GRASP");
     while let Ok(mut reg) = receiver.recv() {
-        reg.connect().unwrap();
+        if args.renew {
+            // back to the renewal loop, enrolled or not
+            return reg.connect(&pledge).map_err(|e| e.to_string());
+        }
+        reg.connect(&pledge).unwrap();
     }
 
         // we get here because sender got dropped
@@ -85,7 +110,11 @@ fn main () -> Result<(), String> {
     let args = args::BootstrapOptions::from_args();
     println!("Options {:?}", args);
 
-    bootstrap(args).unwrap();
+    if args.renew {
+        renew::run(&args, bootstrap).unwrap();
+    } else {
+        bootstrap(&args).unwrap();
+    }
     return Ok(());
 }
 
@@ -4,19 +4,25 @@ use std::fmt;
 use std::io;
 use ureq::{Error, ReadWrite, TlsConnector};
 
-use std::net::TcpStream;
+use std::net::{Shutdown, TcpStream};
 use std::sync::{Arc, Mutex};
 
-use mbedtls::rng::CtrDrbg;
-use mbedtls::ssl::config::{Endpoint, Preset, Transport};
+use mbedtls::alloc::List as MbedtlsList;
+use mbedtls::pk::Pk;
+use mbedtls::ssl::config::{AuthMode, Endpoint, Preset, Transport};
 use mbedtls::ssl::{Config, Context};
+use mbedtls::x509::Certificate;
 
-fn entropy_new() -> mbedtls::rng::OsEntropy {
-    mbedtls::rng::OsEntropy::new()
-}
+use crate::support_rand;
 
+/// one Config, shared by every connection made with it; each connection
+/// gets a Context of its own.  The first handshake decides the peer, every
+/// later connection must present the same certificate chain.
 pub struct MbedTlsConnector {
-    pub context: Arc<Mutex<Context<Box<dyn ReadWrite>>>>,
+    config:     Arc<Config>,
+    peer_chain: Mutex<Vec<Vec<u8>>>,
+    // established by preconnect(), for the next connect() to that host and port
+    ready:      Mutex<Option<(String, Option<u16>, MbedTlsStream)>>,
 }
 
 #[derive(Debug)]
@@ -36,64 +42,136 @@ impl std::error::Error for MbedTlsError {
 #[allow(dead_code)]
 pub(crate) fn default_tls_config() -> std::sync::Arc<dyn TlsConnector> {
     Arc::new(MbedTlsConnector::new(
-        mbedtls::ssl::config::AuthMode::Required,
+        AuthMode::Required,
     ))
 }
 
 impl MbedTlsConnector {
-    pub fn new(mode: mbedtls::ssl::config::AuthMode) -> MbedTlsConnector {
-        let entropy = Arc::new(entropy_new());
-        let mut config = Config::new(Endpoint::Client, Transport::Stream, Preset::Default);
-        let rng = Arc::new(CtrDrbg::new(entropy, None).unwrap());
-        config.set_rng(rng);
-        config.set_authmode(mode);
-        let ctx = Context::new(Arc::new(config));
+    pub fn new(mode: AuthMode) -> MbedTlsConnector {
+        MbedTlsConnector::from_config(client_config(mode))
+    }
+
+    /// a connector that presents `chain`, end-entity first, signed for by
+    /// `key`, and which checks the server against `trust` if it is given
+    pub fn with_identity(mode:  AuthMode,
+                         trust: &[Vec<u8>],
+                         chain: &[Vec<u8>],
+                         key:   Pk) -> Result<MbedTlsConnector, mbedtls::Error> {
+        let mut config = client_config(mode);
+        if !trust.is_empty() {
+            config.set_ca_list(Arc::new(certificate_list(trust)?), None);
+        }
+        config.push_cert(Arc::new(certificate_list(chain)?), Arc::new(key))?;
+        Ok(MbedTlsConnector::from_config(config))
+    }
+
+    fn from_config(config: Config) -> MbedTlsConnector {
         MbedTlsConnector {
-            context: Arc::new(Mutex::new(ctx)),
+            config:     Arc::new(config),
+            peer_chain: Mutex::new(Vec::new()),
+            ready:      Mutex::new(None),
         }
     }
-}
 
-impl TlsConnector for MbedTlsConnector {
-    fn connect(
-        &self,
-        _dns_name: &str,
-        io: Box<dyn ReadWrite>,
-    ) -> Result<Box<dyn ReadWrite>, Error> {
-        let mut ctx = self.context.lock().unwrap();
+    /*
+     * the handshake on `io` now, so that the caller learns the peer
+     * certificates before the first request.  The next connect() to
+     * `dns_name` on the same port uses this session, and drops the
+     * connection it is given instead.
+     */
+    pub fn preconnect(&self, dns_name: &str, io: Box<dyn ReadWrite>) -> Result<Vec<Vec<u8>>, io::Error> {
+        let port = peer_port(io.as_ref());
+        let stream = self.establish(io)?;
+        let chain = stream.peer_chain().to_vec();
+        *self.ready.lock().unwrap() = Some((dns_name.to_string(), port, stream));
+        Ok(chain)
+    }
+
+    /// DER of each certificate the peer presented in the first handshake,
+    /// end-entity first, empty before there was one
+    pub fn peer_chain(&self) -> Vec<Vec<u8>> {
+        self.peer_chain.lock().unwrap().clone()
+    }
+
+    fn check_peer(&self, stream: &MbedTlsStream) -> Result<(), io::Error> {
+        let mut seen = self.peer_chain.lock().unwrap();
+        if seen.is_empty() {
+            *seen = stream.peer_chain().to_vec();
+            return Ok(());
+        }
+        if seen.as_slice() != stream.peer_chain() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "TLS peer certificates changed"));
+        }
+        Ok(())
+    }
+
+    /// a new TLS session over `io`
+    pub fn establish(&self, io: Box<dyn ReadWrite>) -> Result<MbedTlsStream, io::Error> {
+        // the context owns `io` from here on, so keep a second handle on
+        // the socket for timeouts and shutdown
+        let socket = match io.socket() {
+            Some(socket) => Some(socket.try_clone()?),
+            None => None,
+        };
+        let mut ctx = Context::new(self.config.clone());
         match ctx.establish(io, None) {
-            Err(_e) => {
-                let io_err = io::Error::new(io::ErrorKind::InvalidData, MbedTlsError);
-                return Err(io_err.into());
+            Err(_e) => Err(io::Error::new(io::ErrorKind::InvalidData, MbedTlsError)),
+            Ok(()) => {
+                let stream = MbedTlsStream::new(ctx, socket);
+                self.check_peer(&stream)?;
+                Ok(stream)
             }
-            Ok(()) => Ok(MbedTlsStream::new(self)),
         }
     }
 }
 
-struct SyncIo(Mutex<Box<dyn ReadWrite>>);
+fn peer_port(io: &dyn ReadWrite) -> Option<u16> {
+    io.socket()?.peer_addr().ok().map(|addr| addr.port())
+}
 
-impl io::Read for SyncIo {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut lock = self.0.lock().unwrap();
-        lock.read(buf)
-    }
+fn client_config(mode: AuthMode) -> Config {
+    let mut config = Config::new(Endpoint::Client, Transport::Stream, Preset::Default);
+    config.set_rng(support_rand::shared_rng().unwrap());
+    config.set_authmode(mode);
+    config
 }
 
-impl io::Write for SyncIo {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut lock = self.0.lock().unwrap();
-        lock.write(buf)
+fn certificate_list(ders: &[Vec<u8>]) -> Result<MbedtlsList<Certificate>, mbedtls::Error> {
+    let mut list = MbedtlsList::<Certificate>::new();
+    for der in ders {
+        list.push(Certificate::from_der(der)?);
     }
+    Ok(list)
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        let mut lock = self.0.lock().unwrap();
-        lock.flush()
+impl TlsConnector for MbedTlsConnector {
+    fn connect(
+        &self,
+        dns_name: &str,
+        io: Box<dyn ReadWrite>,
+    ) -> Result<Box<dyn ReadWrite>, Error> {
+        let port = peer_port(io.as_ref());
+        {
+            let mut ready = self.ready.lock().unwrap();
+            match ready.take() {
+                Some((host, ready_port, stream)) if host == dns_name && ready_port == port => {
+                    return Ok(Box::new(stream));
+                },
+                // for another host, keep it for the one it was made for
+                other => *ready = other,
+            }
+        }
+        Ok(Box::new(self.establish(io)?))
     }
 }
 
-struct MbedTlsStream {
-    context: Arc<Mutex<Context<Box<dyn ReadWrite>>>>, //tcp_stream: TcpStream,
+pub struct MbedTlsStream {
+    // ureq wants ReadWrite to be Sync, and Context is not: it holds the
+    // raw mbedtls_ssl_context.  Everything here takes &mut self, so the
+    // Mutex is never contended and get_mut() is enough.
+    context:    Mutex<Context<Box<dyn ReadWrite>>>,
+    socket:     Option<TcpStream>,
+    peer_chain: Vec<Vec<u8>>,
 }
 
 impl fmt::Debug for MbedTlsStream {
@@ -103,10 +181,35 @@ impl fmt::Debug for MbedTlsStream {
 }
 
 impl MbedTlsStream {
-    pub fn new(mtc: &MbedTlsConnector) -> Box<MbedTlsStream> {
-        Box::new(MbedTlsStream {
-            context: mtc.context.clone(),
-        })
+    fn new(ctx: Context<Box<dyn ReadWrite>>, socket: Option<TcpStream>) -> MbedTlsStream {
+        let peer_chain = match ctx.peer_cert() {
+            Ok(Some(certificates)) => {
+                certificates.into_iter().map(|cert| cert.as_der().to_vec()).collect()
+            },
+            _ => Vec::new()
+        };
+        MbedTlsStream {
+            context:    Mutex::new(ctx),
+            socket:     socket,
+            peer_chain: peer_chain,
+        }
+    }
+
+    /// DER of each certificate the peer presented in the handshake,
+    /// end-entity first
+    pub fn peer_chain(&self) -> &[Vec<u8>] {
+        &self.peer_chain
+    }
+
+    /// send close_notify, then shut the socket down in both directions
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        match self.socket.take() {
+            Some(socket) => {
+                self.context.get_mut().unwrap().close();
+                socket.shutdown(Shutdown::Both)
+            },
+            None => Ok(()),
+        }
     }
 
     //    pub fn get_peer_certificate(&self) -> Result<(), Error> {
@@ -115,31 +218,34 @@ impl MbedTlsStream {
 
 }
 
+// streams end up in ureq's pool, which just drops them
+impl Drop for MbedTlsStream {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
 impl ReadWrite for MbedTlsStream {
-    // no obvious way to get socket back out of mbedtls context
-    // context.io() returns Any, which is hard to turn back into
-    // TcpStream reference, and what is lifetime of reference?
+    // a clone of the socket the context was established on, so that
+    // ureq can set timeouts on it
     fn socket(&self) -> Option<&TcpStream> {
-        None
+        self.socket.as_ref()
     }
 }
 
 impl io::Read for MbedTlsStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut ctx = self.context.lock().unwrap();
-        ctx.read(buf)
+        self.context.get_mut().unwrap().read(buf)
     }
 }
 
 impl io::Write for MbedTlsStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut ctx = self.context.lock().unwrap();
-        ctx.write(buf)
+        self.context.get_mut().unwrap().write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        let mut ctx = self.context.lock().unwrap();
-        ctx.flush()
+        self.context.get_mut().unwrap().flush()
     }
 }
 
@@ -0,0 +1,336 @@
+/*
+ * Copyright [2021] <mcr@sandelman.ca>
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+ *
+ */
+
+/*
+ * LDevID renewal, RFC7030 section 4.2.2.  Once enrolled, the pledge is a
+ * member of the domain: it authenticates with its LDevID, trusts the
+ * domain CA it got from /cacerts, and asks the registrar for a new
+ * certificate well before the current one runs out.  If the domain will
+ * not renew it, the device starts over with BRSKI.
+ */
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use mbedtls::pk::Pk;
+use mbedtls::ssl::config::AuthMode;
+
+use crate::args::BootstrapOptions;
+use crate::asn1;
+use crate::csr;
+use crate::est::{self, EstError};
+use crate::https::Session;
+use crate::mbedtls_connector::MbedTlsConnector;
+use crate::utils;
+use crate::voucher_validation::{self, PinnedDomain};
+
+// never sleep longer than this at once, the clock may be stepped
+const MAX_SLEEP:   u64 = 3600;
+// wait after a failure that is not a refusal by the domain
+const RETRY_AFTER: u64 = 300;
+// a domain that refuses the LDevID in the TLS handshake (expired, revoked)
+// looks like any failed connection; this many in a row, and BRSKI it is
+const MAX_CONNECTION_FAILURES: u32 = 3;
+
+#[derive(Debug)]
+pub enum RenewError {
+    NotEnrolled(String),
+    KeyMismatch,
+    NoRegistrar,
+    IoError(io::Error),
+    TlsFailed,
+    Rejected(u16),
+    Expired,
+    NotPinned,
+    EstFailed(EstError),
+}
+
+impl RenewError {
+    /// the domain will not renew this LDevID, BRSKI has to be done again.
+    /// Other 4xx, like 408 or 429, are worth another try later.
+    pub fn is_rejection(&self) -> bool {
+        match self {
+            RenewError::Rejected(401) | RenewError::Rejected(403) => true,
+            RenewError::Expired | RenewError::NotPinned => true,
+            _ => false,
+        }
+    }
+
+    /// no HTTP answer at all: the connection or its handshake failed
+    pub fn is_connection_failure(&self) -> bool {
+        matches!(self, RenewError::EstFailed(EstError::IoError(_)))
+    }
+}
+
+impl fmt::Display for RenewError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenewError::NotEnrolled(why) => {
+                write!(f, "not enrolled: {}", why)
+            },
+            RenewError::KeyMismatch => {
+                write!(f, "LDevID private key does not match the certificate")
+            },
+            RenewError::NoRegistrar => {
+                write!(f, "renewal needs the registrar, use --registrar")
+            },
+            RenewError::IoError(error) => {
+                write!(f, "renewal IO error {}", error)
+            },
+            RenewError::TlsFailed => {
+                write!(f, "TLS with the LDevID failed")
+            },
+            RenewError::Rejected(status) => {
+                write!(f, "simplereenroll rejected with status {}", status)
+            },
+            RenewError::Expired => {
+                write!(f, "LDevID has expired")
+            },
+            RenewError::NotPinned => {
+                write!(f, "trust store does not match the pinned domain")
+            },
+            RenewError::EstFailed(error) => {
+                write!(f, "{}", error)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for RenewError {
+    fn from(error: io::Error) -> Self {
+        RenewError::IoError(error)
+    }
+}
+impl From<EstError> for RenewError {
+    fn from(error: EstError) -> Self {
+        match error {
+            EstError::Rejected(status) => RenewError::Rejected(status),
+            _ => RenewError::EstFailed(error),
+        }
+    }
+}
+
+/// the certificate chain and key written by enrollment
+pub struct LDevID {
+    pub chain:      Vec<Vec<u8>>,
+    pub key:        Pk,
+    pub subject:    Vec<u8>,
+    pub not_before: u64,
+    pub not_after:  u64,
+}
+
+impl LDevID {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<LDevID, RenewError> {
+        let bad = |why: &str| RenewError::NotEnrolled(format!("{}: {}", cert_path.display(), why));
+
+        let chain = utils::pem_blocks(&fs::read(cert_path)?);
+        let cert = chain.first().ok_or_else(|| bad("no certificate"))?;
+        let parts = asn1::parse_certificate(cert).or_else(|_| Err(bad("certificate malformed")))?;
+        let (not_before, not_after) = asn1::validity(&parts).ok_or_else(|| bad("bad validity"))?;
+        let subject = parts.subject.raw.to_vec();
+        let spki = parts.spki.raw.to_vec();
+
+        let key_der = utils::pem_to_der(&fs::read(key_path)?).ok_or_else(|| bad("private key not PEM"))?;
+        let mut key = Pk::from_private_key(&key_der, None).or_else(|_| Err(bad("private key not parsed")))?;
+        if key.write_public_der_vec().ok() != Some(spki) {
+            return Err(RenewError::KeyMismatch);
+        }
+
+        Ok(LDevID {
+            chain:      chain,
+            key:        key,
+            subject:    subject,
+            not_before: not_before,
+            not_after:  not_after,
+        })
+    }
+}
+
+/*
+ * est::write_ldevid() replaces the key, then the certificate.  Should it
+ * stop in between, the key is new and the certificate old: put the .old
+ * pair back, if that one matches.  The .old files stay, so this can be
+ * interrupted too.
+ */
+pub fn load_ldevid(cert_path: &Path, key_path: &Path) -> Result<LDevID, RenewError> {
+    match LDevID::load(cert_path, key_path) {
+        Err(RenewError::KeyMismatch) => {
+            let old_cert = est::with_suffix(cert_path, ".old");
+            let old_key = est::with_suffix(key_path, ".old");
+            let ldevid = LDevID::load(&old_cert, &old_key)?;
+            est::write_atomically(key_path, &fs::read(&old_key)?, 0o600)?;
+            est::write_atomically(cert_path, &fs::read(&old_cert)?, 0o644)?;
+            println!("LDevID key and certificate did not match, went back to the previous pair");
+            Ok(ldevid)
+        },
+        loaded => loaded,
+    }
+}
+
+/// `fraction` of the way from notBefore to notAfter, args checked that it is in (0,1]
+pub fn renewal_time(not_before: u64, not_after: u64, fraction: f64) -> u64 {
+    let lifetime = not_after.saturating_sub(not_before);
+    not_before + (lifetime as f64 * fraction) as u64
+}
+
+fn ldevid_paths(options: &BootstrapOptions) -> Result<(&Path, &Path), RenewError> {
+    match (&options.ldevid_cert, &options.ldevid_priv) {
+        (Some(cert), Some(key)) => Ok((cert, key)),
+        _ => Err(RenewError::NotEnrolled("renewal needs --ldevid-cert and --ldevid-priv".to_string())),
+    }
+}
+
+/// one simplereenroll with the current LDevID, replacing it on success
+pub fn reenroll(options: &BootstrapOptions, ldevid: LDevID) -> Result<(), RenewError> {
+    let (cert_path, key_path) = ldevid_paths(options)?;
+    let url = options.registrar.as_ref().ok_or(RenewError::NoRegistrar)?;
+    let host = url.host_str().ok_or(RenewError::NoRegistrar)?;
+    let authority = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+
+    let trust_path = options.trust_store.as_ref()
+        .ok_or_else(|| RenewError::NotEnrolled("no --trust-store, the domain CA is unknown".to_string()))?;
+    let mut cacerts = utils::pem_blocks(&fs::read(trust_path)?);
+    if let Some(pinned_path) = &options.pinned_domain {
+        let pinned = PinnedDomain::from_pem(&fs::read(pinned_path)?).ok_or(RenewError::NotPinned)?;
+        cacerts = est::pinned_cacerts(&cacerts, &pinned);
+        if cacerts.is_empty() {
+            return Err(RenewError::NotPinned);
+        }
+    }
+
+    let key_type = csr::key_type_of(&ldevid.key).unwrap_or(csr::DEFAULT_KEY_TYPE);
+    let connector = MbedTlsConnector::with_identity(AuthMode::Required, &cacerts, &ldevid.chain, ldevid.key)
+        .or(Err(RenewError::TlsFailed))?;
+    let session = Session::new(connector, &authority);
+
+    let attrs = est::fetch_csrattrs(&session)?;
+    let mut key = csr::generate_key(attrs.key_type.unwrap_or(key_type))
+        .map_err(|e| EstError::CsrFailed(e.into()))?;
+    let request = csr::build_csr_for(&mut key, &ldevid.subject, &attrs, options.challenge_password.as_deref())
+        .map_err(EstError::CsrFailed)?;
+
+    let issued = est::enroll(&session, est::EST_SIMPLEREENROLL, &request)?;
+    let spki = key.write_public_der_vec().map_err(|e| EstError::CsrFailed(e.into()))?;
+    let chain = est::issued_chain(&issued, &spki, &cacerts)?;
+
+    let key_pem = key.write_private_pem_string().map_err(|e| EstError::CsrFailed(e.into()))?;
+    est::write_ldevid(cert_path, key_path, &chain, key_pem.as_bytes())?;
+    Ok(())
+}
+
+/*
+ * bootstrap if there is no LDevID, then keep renewing it for as long as
+ * the process runs.
+ */
+pub fn run(options:   &BootstrapOptions,
+           bootstrap: fn(&BootstrapOptions) -> Result<(), String>) -> Result<(), String> {
+    let (cert_path, key_path) = ldevid_paths(options).map_err(|e| e.to_string())?;
+
+    let rebootstrap = |why: &RenewError| {
+        println!("{}, starting over with BRSKI", why);
+        if let Err(error) = bootstrap(options) {
+            println!("bootstrap failed: {}", error);
+            thread::sleep(Duration::from_secs(RETRY_AFTER));
+        }
+    };
+
+    let mut connection_failures = 0;
+    loop {
+        let ldevid = match load_ldevid(cert_path, key_path) {
+            Ok(ldevid) => ldevid,
+            Err(error) => {
+                rebootstrap(&error);
+                continue;
+            }
+        };
+
+        let now = voucher_validation::now();
+        let due = renewal_time(ldevid.not_before, ldevid.not_after, options.renew_fraction);
+        if now < due {
+            println!("LDevID renewal due in {} seconds", due - now);
+            thread::sleep(Duration::from_secs((due - now).min(MAX_SLEEP)));
+            continue;
+        }
+
+        let result = if now >= ldevid.not_after {
+            Err(RenewError::Expired)
+        } else {
+            reenroll(options, ldevid)
+        };
+        connection_failures = match &result {
+            Err(error) if error.is_connection_failure() => connection_failures + 1,
+            _ => 0,
+        };
+        match result {
+            Ok(()) => println!("LDevID renewed, written to {}", cert_path.display()),
+            Err(error) if error.is_rejection() => rebootstrap(&error),
+            Err(error) if connection_failures >= MAX_CONNECTION_FAILURES => {
+                connection_failures = 0;
+                println!("{} connections with the LDevID failed in a row", MAX_CONNECTION_FAILURES);
+                rebootstrap(&error);
+            },
+            Err(error) => {
+                println!("LDevID renewal failed: {}, retrying in {} seconds", error, RETRY_AFTER);
+                thread::sleep(Duration::from_secs(RETRY_AFTER));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn renewal_fraction() {
+        assert_eq!(renewal_time(1000, 2000, 0.75), 1750);
+        assert_eq!(renewal_time(1000, 2000, 1.0), 2000);
+        // a certificate that expires before it starts is due at its notBefore
+        assert_eq!(renewal_time(2000, 1000, 0.75), 2000);
+    }
+
+    #[test]
+    fn rejection_means_rebootstrap() {
+        assert!(RenewError::from(EstError::Rejected(403)).is_rejection());
+        assert!(RenewError::Expired.is_rejection());
+        assert!(RenewError::NotPinned.is_rejection());
+        assert!(RenewError::from(EstError::Rejected(401)).is_rejection());
+        assert!(!RenewError::from(EstError::Rejected(408)).is_rejection());
+        assert!(!RenewError::from(EstError::Rejected(429)).is_rejection());
+        assert!(!RenewError::from(EstError::Rejected(503)).is_rejection());
+        assert!(!RenewError::TlsFailed.is_rejection());
+
+        let refused = io::Error::new(io::ErrorKind::InvalidData, "MedTLS handshake failed");
+        assert!(RenewError::from(EstError::IoError(refused)).is_connection_failure());
+        assert!(!RenewError::from(EstError::Rejected(503)).is_connection_failure());
+        assert!(!RenewError::IoError(io::Error::from(io::ErrorKind::NotFound)).is_connection_failure());
+    }
+}
+
+/*
+ * Local Variables:
+ * mode: rust
+ * compile-command: "cd .. && cargo build"
+ * End:
+ */
@@ -9,42 +9,77 @@
  * according to those terms. */
 
 use std::os::raw::*;
+use std::sync::{Arc, Mutex};
+
+use mbedtls::rng::{CtrDrbg, OsEntropy, RngCallback, RngCallbackMut};
 
 #[allow(non_camel_case_types)]
 type size_t = usize;
 
-use rand::{Rng, XorShiftRng};
+/*
+ * The one random generator for the whole process: a CtrDrbg seeded from
+ * OsEntropy, shared by the TLS configuration and by every signature.
+ */
+static SHARED_RNG: Mutex<Option<Arc<CtrDrbg>>> = Mutex::new(None);
+
+pub fn shared_rng() -> Result<Arc<CtrDrbg>, mbedtls::Error> {
+    let mut shared = SHARED_RNG.lock().unwrap();
+    if let Some(rng) = shared.as_ref() {
+        return Ok(rng.clone());
+    }
+    let rng = Arc::new(CtrDrbg::new(Arc::new(OsEntropy::new()), None)?);
+    *shared = Some(rng.clone());
+    Ok(rng)
+}
+
+/// the shared generator, for the APIs that want a `&mut` random source
+pub struct SharedRandom(Arc<CtrDrbg>);
 
-/// Not cryptographically secure!!! Use for testing only!!!
-pub struct TestRandom(XorShiftRng);
+pub fn signing_rng() -> Result<SharedRandom, mbedtls::Error> {
+    Ok(SharedRandom(shared_rng()?))
+}
 
-impl mbedtls::rng::RngCallbackMut for TestRandom {
+impl RngCallbackMut for SharedRandom {
     unsafe extern "C" fn call_mut(p_rng: *mut c_void, data: *mut c_uchar, len: size_t) -> c_int {
-        (*(p_rng as *mut TestRandom))
-            .0
-            .fill_bytes(core::slice::from_raw_parts_mut(data, len));
-        0
+        <CtrDrbg as RngCallback>::call(p_rng, data, len)
     }
 
     fn data_ptr_mut(&mut self) -> *mut c_void {
-        self as *const _ as *mut _
+        self.0.data_ptr()
     }
 }
 
-impl mbedtls::rng::RngCallback for TestRandom {
+impl RngCallback for SharedRandom {
     unsafe extern "C" fn call(p_rng: *mut c_void, data: *mut c_uchar, len: size_t) -> c_int {
-        (*(p_rng as *mut TestRandom))
-            .0
-            .fill_bytes(core::slice::from_raw_parts_mut(data, len));
-        0
+        <CtrDrbg as RngCallback>::call(p_rng, data, len)
     }
 
     fn data_ptr(&self) -> *mut c_void {
-        self as *const _ as *mut _
+        self.0.data_ptr()
     }
 }
 
-/// Not cryptographically secure!!! Use for testing only!!!
-pub fn test_rng() -> TestRandom {
-    TestRandom(XorShiftRng::new_unseeded())
+/// fill a fresh buffer from the shared generator
+pub fn random_bytes(len: usize) -> Result<Vec<u8>, mbedtls::Error> {
+    let rng = shared_rng()?;
+    let mut bytes = vec![0u8; len];
+    rng.random(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn one_shared_generator() {
+        let first = shared_rng().unwrap();
+        let second = shared_rng().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        let a = random_bytes(16).unwrap();
+        let b = random_bytes(16).unwrap();
+        assert_eq!(a.len(), 16);
+        assert_ne!(a, b);
+    }
 }
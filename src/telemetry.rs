@@ -0,0 +1,255 @@
+/*
+ * Copyright [2021] <mcr@sandelman.ca>
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+ *
+ */
+
+/*
+ * RFC8995 sections 5.7 and 5.9.4: the pledge tells the registrar whether
+ * it took the voucher, and then whether enrollment worked, and if not,
+ * why.  JSON in CMS SignedData as the RFC has it, or CBOR in COSE_Sign1
+ * for the constrained vouchers.  The registrar need not answer, so nothing
+ * here can fail the bootstrap.
+ */
+
+use std::fmt;
+use std::io;
+
+use serde_json::{json, Map, Value};
+
+use crate::args::VoucherFormat;
+use crate::cbor::{self, CborValue};
+use crate::cms;
+use crate::cose;
+use crate::custom_voucher::{self, VoucherAlgorithm};
+use crate::https::Session;
+use crate::voucher_validation::VoucherVerdict;
+
+pub static VOUCHER_STATUS: &str = "/.well-known/brski/voucher_status";
+pub static ENROLL_STATUS: &str = "/.well-known/brski/enrollstatus";
+// RFC8995 section 5.7 labels the CMS signed JSON as plain JSON
+pub static STATUS_JSON_CONTENT_TYPE: &str = "application/json";
+pub static STATUS_COSE_CONTENT_TYPE: &str = "application/cose";
+
+const STATUS_VERSION: i64 = 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StatusEncoding {
+    Json,
+    Cbor,
+}
+
+impl StatusEncoding {
+    /// CBOR to a registrar that speaks COSE vouchers, otherwise JSON
+    pub fn for_voucher_format(format: VoucherFormat) -> StatusEncoding {
+        match format {
+            VoucherFormat::Cose => StatusEncoding::Cbor,
+            _ => StatusEncoding::Json,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TelemetryError {
+    SigningFailed,
+    IoError(io::Error),
+}
+
+impl fmt::Display for TelemetryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TelemetryError::SigningFailed => {
+                write!(f, "status report could not be signed")
+            },
+            TelemetryError::IoError(error) => {
+                write!(f, "status report not sent: {}", error)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for TelemetryError {
+    fn from(error: io::Error) -> Self {
+        TelemetryError::IoError(error)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct StatusReport {
+    pub status: bool,
+    /// human readable
+    pub reason: String,
+    /// the typed reason, for the registrar to sort failures by
+    pub reason_context: Option<String>,
+}
+
+impl StatusReport {
+    pub fn from_verdict(verdict: VoucherVerdict) -> StatusReport {
+        StatusReport {
+            status:         verdict.is_accepted(),
+            reason:         verdict.to_string(),
+            reason_context: Some(verdict.reason().to_string()),
+        }
+    }
+
+    pub fn enrolled() -> StatusReport {
+        StatusReport {
+            status:         true,
+            reason:         "LDevID enrolled".to_string(),
+            reason_context: None,
+        }
+    }
+
+    pub fn enroll_failed(reason: String) -> StatusReport {
+        StatusReport {
+            status:         false,
+            reason:         reason,
+            reason_context: None,
+        }
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        let mut report = Map::new();
+        report.insert("version".to_string(), json!(STATUS_VERSION));
+        report.insert("status".to_string(), json!(self.status));
+        report.insert("reason".to_string(), json!(self.reason));
+        if let Some(context) = &self.reason_context {
+            report.insert("reason-context".to_string(), json!({ "reason": context }));
+        }
+        serde_json::to_vec(&Value::Object(report)).unwrap()
+    }
+
+    /// the same names as the JSON, as CBOR text
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let text = |s: &str| CborValue::Text(s.to_string());
+        let mut report = vec![
+            (text("version"), CborValue::Integer(STATUS_VERSION)),
+            (text("status"),  CborValue::Bool(self.status)),
+            (text("reason"),  text(&self.reason)),
+        ];
+        if let Some(context) = &self.reason_context {
+            report.push((text("reason-context"), CborValue::Map(vec![(text("reason"), text(context))])));
+        }
+        cbor::encode(&CborValue::Map(report))
+    }
+
+    /// the Content-Type and the signed body
+    pub fn sign(&self,
+                encoding: StatusEncoding,
+                cert_der: &[u8],
+                key_pem:  &[u8],
+                alg:      VoucherAlgorithm) -> Result<(&'static str, Vec<u8>), TelemetryError> {
+        match encoding {
+            StatusEncoding::Json => {
+                let der = cms::sign(cms::OID_DATA, &self.to_json(), cert_der, key_pem, alg)
+                    .or(Err(TelemetryError::SigningFailed))?;
+                Ok((STATUS_JSON_CONTENT_TYPE, der))
+            },
+            StatusEncoding::Cbor => {
+                let protected = cose::protected_header(cose::alg_id(alg));
+                let payload = self.to_cbor();
+                let signature = custom_voucher::sign_detached(key_pem, alg, &cose::sig_structure(&protected, &payload))
+                    .or(Err(TelemetryError::SigningFailed))?;
+                let sign1 = cose::Sign1 {
+                    protected:   protected,
+                    unprotected: CborValue::Map(Vec::new()),
+                    payload:     payload,
+                    signature:   signature,
+                    tagged:      true,
+                };
+                Ok((STATUS_COSE_CONTENT_TYPE, sign1.encode()))
+            }
+        }
+    }
+}
+
+/// POST a signed report, and return the HTTP status the registrar gave
+pub fn post_report(session: &Session,
+                   path:    &str,
+                   signed:  (&str, Vec<u8>)) -> Result<u16, TelemetryError> {
+    let (content_type, body) = signed;
+    let resp = session.request("POST", path, &[("Content-Type", content_type)], &body)?;
+    Ok(resp.status)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::test_fixtures::{ed25519_cert, ed25519_key_pem};
+
+    #[test]
+    fn rejected_voucher_report() {
+        let report = StatusReport::from_verdict(VoucherVerdict::NonceMismatch);
+        assert!(!report.status);
+
+        let json: Value = serde_json::from_slice(&report.to_json()).unwrap();
+        assert_eq!(json["version"], json!(1));
+        assert_eq!(json["status"], json!(false));
+        assert_eq!(json["reason"], json!("voucher nonce does not match voucher-request"));
+        assert_eq!(json["reason-context"]["reason"], json!("nonce-mismatch"));
+
+        let report = StatusReport::from_verdict(VoucherVerdict::Malformed);
+        assert_eq!(report.reason_context.as_deref(), Some("malformed"));
+
+        let cbor = cbor::decode(&report.to_cbor()).unwrap();
+        match cbor {
+            CborValue::Map(entries) => {
+                assert_eq!(entries[1], (CborValue::Text("status".to_string()), CborValue::Bool(false)));
+                assert_eq!(entries.len(), 4);
+            },
+            _ => panic!("not a map"),
+        }
+    }
+
+    #[test]
+    fn enroll_status_report() {
+        let json: Value = serde_json::from_slice(&StatusReport::enrolled().to_json()).unwrap();
+        assert_eq!(json["status"], json!(true));
+        assert!(json.get("reason-context").is_none());
+
+        let report = StatusReport::enroll_failed("EST request rejected with status 403".to_string());
+        let json: Value = serde_json::from_slice(&report.to_json()).unwrap();
+        assert_eq!(json["status"], json!(false));
+        assert_eq!(json["reason"], json!("EST request rejected with status 403"));
+    }
+
+    #[test]
+    fn signed_reports() {
+        let report = StatusReport::from_verdict(VoucherVerdict::Accepted);
+        let cert = ed25519_cert();
+
+        let (content_type, der) = report.sign(StatusEncoding::Json, &cert, &ed25519_key_pem(),
+                                              VoucherAlgorithm::EdDSA).unwrap();
+        assert_eq!(content_type, STATUS_JSON_CONTENT_TYPE);
+        let signed = cms::SignedData::decode(&der).unwrap();
+        assert_eq!(signed.content, report.to_json());
+        assert!(signed.verify(&cert));
+
+        let (content_type, raw) = report.sign(StatusEncoding::Cbor, &cert, &ed25519_key_pem(),
+                                              VoucherAlgorithm::EdDSA).unwrap();
+        assert_eq!(content_type, STATUS_COSE_CONTENT_TYPE);
+        let sign1 = cose::Sign1::decode(&raw).unwrap();
+        assert_eq!(sign1.alg(), Some(cose::ALG_EDDSA));
+        assert_eq!(sign1.payload, report.to_cbor());
+        assert!(custom_voucher::verify_detached(&cert, VoucherAlgorithm::EdDSA,
+                                                &sign1.to_be_signed(), &sign1.signature));
+    }
+}
+
+/*
+ * Local Variables:
+ * mode: rust
+ * compile-command: "cd .. && cargo build"
+ * End:
+ */
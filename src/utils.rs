@@ -9,40 +9,227 @@ macro_rules! null_terminate_bytes {
 
 pub use null_terminate_bytes;
 
-use std::io::{self, Cursor, Write};
-
-pub fn asn1_signature_from(sig: &[u8]) -> io::Result<Vec<u8>> {
-    let sig_len = sig.len();
-    let half = sig_len / 2;
-    let h = half as u8;
-
-    let mut asn1 = vec![0u8; sig_len + 8];
-    let mut writer = Cursor::new(&mut asn1[..]);
-    writer.write(&[48, 2 * h + 6, 2, h + 1, 0])?;
-    writer.write(&sig[..half])?; // r
-    writer.write(&[2, h + 1, 0])?;
-    writer.write(&sig[half..])?; // s
-
-    Ok(asn1)
-}
-
-pub fn is_asn1_signature(sig: &[u8]) -> bool {
-    let sig_len = sig.len();
-    let seq_len = sig_len - 2;
-
-    let int1_pos = 2;
-    let int1_len = sig.get(int1_pos + 1);
-    if int1_len.is_none() { return false; }
-    let int1_len = *int1_len.unwrap() as usize;
-
-    let int2_pos = int1_pos + 1 + int1_len + 1;
-    let int2_len = sig.get(int2_pos + 1);
-    if int2_len.is_none() { return false; }
-    let int2_len = *int2_len.unwrap() as usize;
-
-    sig[0] == 48 &&
-        sig[1] as usize == seq_len &&
-        sig[int1_pos] == 2 &&
-        sig[int2_pos] == 2 &&
-        int1_len + int2_len + 4 == seq_len
+/// the DER contents of every PEM block found in `pem`, in order
+pub fn pem_blocks(pem: &[u8]) -> Vec<Vec<u8>> {
+    let text = String::from_utf8_lossy(pem);
+    let mut blocks = Vec::new();
+    let mut body: Option<String> = None;
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.starts_with("-----BEGIN ") {
+            body = Some(String::new());
+        } else if line.starts_with("-----END ") {
+            if let Some(b64) = body.take() {
+                if let Ok(der) = base64::decode(&b64) {
+                    blocks.push(der);
+                }
+            }
+        } else if let Some(b64) = body.as_mut() {
+            // skip RFC1421 style headers
+            if !line.contains(':') {
+                b64.push_str(line);
+            }
+        }
+    }
+    blocks
+}
+
+pub fn pem_to_der(pem: &[u8]) -> Option<Vec<u8>> {
+    pem_blocks(pem).into_iter().next()
+}
+
+/// a certificate given either as PEM or as DER, as DER
+pub fn cert_der(cert: &[u8]) -> Option<Vec<u8>> {
+    if cert.starts_with(b"-----BEGIN") {
+        pem_to_der(cert)
+    } else {
+        Some(cert.to_vec())
+    }
+}
+
+pub fn der_to_pem(label: &str, der: &[u8]) -> String {
+    let b64 = base64::encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in b64.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+use std::io;
+
+/*
+ * ECDSA signatures come in two shapes: COSE (RFC8152 section 8.1) uses the
+ * fixed size r||s, mbedtls and X.509 use DER SEQUENCE { INTEGER r, INTEGER s }.
+ * Both directions are strict: minimal INTEGERs, and no trailing data.
+ */
+
+/// field sizes, in bytes, of P-256, P-384 and P-521
+pub const ECDSA_FIELD_LENS: [usize; 3] = [32, 48, 66];
+
+fn invalid_signature() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed ECDSA signature")
+}
+
+fn push_der_integer(out: &mut Vec<u8>, unsigned: &[u8]) {
+    let mut v = unsigned;
+    while v.len() > 1 && v[0] == 0 {
+        v = &v[1..];
+    }
+    let pad = v[0] & 0x80 != 0;
+    out.push(0x02);
+    out.push((v.len() + pad as usize) as u8);
+    if pad {
+        out.push(0x00);
+    }
+    out.extend_from_slice(v);
+}
+
+pub fn ecdsa_raw_to_der(raw: &[u8]) -> io::Result<Vec<u8>> {
+    let half = raw.len() / 2;
+    if raw.len() % 2 != 0 || !ECDSA_FIELD_LENS.contains(&half) {
+        return Err(invalid_signature());
+    }
+
+    let mut body = Vec::with_capacity(raw.len() + 6);
+    push_der_integer(&mut body, &raw[..half]); // r
+    push_der_integer(&mut body, &raw[half..]); // s
+
+    let mut der = vec![0x30];
+    if body.len() >= 0x80 {
+        der.push(0x81);
+    }
+    der.push(body.len() as u8);
+    der.extend_from_slice(&body);
+    Ok(der)
+}
+
+fn der_sequence_body(der: &[u8]) -> io::Result<&[u8]> {
+    match der {
+        [0x30, len, body @ ..] if *len < 0x80 && *len as usize == body.len() => Ok(body),
+        [0x30, 0x81, len, body @ ..] if *len >= 0x80 && *len as usize == body.len() => Ok(body),
+        _ => Err(invalid_signature()),
+    }
+}
+
+/// returns the unsigned value, and what follows the INTEGER
+fn der_integer_value(buf: &[u8]) -> io::Result<(&[u8], &[u8])> {
+    let (len, rest) = match buf {
+        [0x02, len, rest @ ..] if *len > 0 && *len < 0x80 && *len as usize <= rest.len() => (*len as usize, rest),
+        _ => return Err(invalid_signature()),
+    };
+    let (v, rest) = rest.split_at(len);
+
+    if v[0] & 0x80 != 0 {
+        return Err(invalid_signature());  // negative
+    }
+    if v.len() > 1 && v[0] == 0 {
+        if v[1] & 0x80 == 0 {
+            return Err(invalid_signature());  // not minimal
+        }
+        return Ok((&v[1..], rest));
+    }
+    Ok((v, rest))
+}
+
+pub fn ecdsa_der_to_raw(der: &[u8], field_len: usize) -> io::Result<Vec<u8>> {
+    let (r, rest) = der_integer_value(der_sequence_body(der)?)?;
+    let (s, rest) = der_integer_value(rest)?;
+    if !rest.is_empty() || r.len() > field_len || s.len() > field_len {
+        return Err(invalid_signature());
+    }
+
+    let mut raw = vec![0u8; 2 * field_len];
+    raw[field_len - r.len()..field_len].copy_from_slice(r);
+    raw[2 * field_len - s.len()..].copy_from_slice(s);
+    Ok(raw)
+}
+
+pub fn is_der_signature(sig: &[u8]) -> bool {
+    ecdsa_der_to_raw(sig, ECDSA_FIELD_LENS[2]).is_ok()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // RFC6979 section A.2.5, P-256 with SHA-256, message "sample"
+    static R: [u8; 32] = [
+        0xef, 0xd4, 0x8b, 0x2a, 0xac, 0xb6, 0xa8, 0xfd, 0x11, 0x40, 0xdd, 0x9c, 0xd4, 0x5e, 0x81, 0xd6,
+        0x9d, 0x2c, 0x87, 0x7b, 0x56, 0xaa, 0xf9, 0x91, 0xc3, 0x4d, 0x0e, 0xa8, 0x4e, 0xaf, 0x37, 0x16,
+    ];
+    static S: [u8; 32] = [
+        0xf7, 0xcb, 0x1c, 0x94, 0x2d, 0x65, 0x7c, 0x41, 0xd4, 0x36, 0xc7, 0xa1, 0xb6, 0xe2, 0x9f, 0x65,
+        0xf3, 0xe9, 0x00, 0xdb, 0xb9, 0xaf, 0xf4, 0x06, 0x4d, 0xc4, 0xab, 0x2f, 0x84, 0x3a, 0xcd, 0xa8,
+    ];
+
+    #[test]
+    fn p256_high_bits_padded() {
+        let raw = [&R[..], &S[..]].concat();
+        let der = ecdsa_raw_to_der(&raw).unwrap();
+
+        let mut expected = vec![0x30, 0x46, 0x02, 0x21, 0x00];
+        expected.extend_from_slice(&R);
+        expected.extend_from_slice(&[0x02, 0x21, 0x00]);
+        expected.extend_from_slice(&S);
+        assert_eq!(der, expected);
+
+        assert!(is_der_signature(&der));
+        assert_eq!(ecdsa_der_to_raw(&der, 32).unwrap(), raw);
+    }
+
+    #[test]
+    fn p256_minimal_integers() {
+        // r has its high bit clear, s has leading zero bytes
+        let mut raw = vec![0x7f; 32];
+        raw.extend_from_slice(&[0x00, 0x00, 0x80]);
+        raw.extend_from_slice(&[0x01; 29]);
+
+        let der = ecdsa_raw_to_der(&raw).unwrap();
+        assert_eq!(&der[..4], &[0x30, 0x43, 0x02, 0x20]);
+        assert_eq!(&der[36..40], &[0x02, 0x1f, 0x00, 0x80]);
+        assert_eq!(der.len(), 2 + 0x43);
+        assert_eq!(ecdsa_der_to_raw(&der, 32).unwrap(), raw);
+    }
+
+    #[test]
+    fn p521_long_form_length() {
+        let mut raw = vec![0x01];
+        raw.extend_from_slice(&[0xff; 65]);
+        raw.extend_from_slice(&[0x00, 0x00]);
+        raw.extend_from_slice(&[0x01; 64]);
+
+        let der = ecdsa_raw_to_der(&raw).unwrap();
+        assert_eq!(&der[..5], &[0x30, 0x81, 0x86, 0x02, 0x42]);
+        assert_eq!(der.len(), 3 + 0x86);
+        assert_eq!(ecdsa_der_to_raw(&der, 66).unwrap(), raw);
+        assert!(ecdsa_der_to_raw(&der, 48).is_err());
+    }
+
+    #[test]
+    fn p384_round_trip() {
+        let raw: Vec<u8> = (0..96).map(|i| i as u8 ^ 0xa5).collect();
+        let der = ecdsa_raw_to_der(&raw).unwrap();
+        assert_eq!(ecdsa_der_to_raw(&der, 48).unwrap(), raw);
+    }
+
+    #[test]
+    fn malformed_signatures() {
+        assert!(!is_der_signature(&[]));
+        assert!(!is_der_signature(&[0x30]));
+        assert!(!is_der_signature(&[0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01]));
+        // non-minimal and negative INTEGERs
+        assert!(!is_der_signature(&[0x30, 0x07, 0x02, 0x02, 0x00, 0x01, 0x02, 0x01, 0x01]));
+        assert!(!is_der_signature(&[0x30, 0x06, 0x02, 0x01, 0x81, 0x02, 0x01, 0x01]));
+        // trailing data
+        assert!(!is_der_signature(&[0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x01, 0x00]));
+        assert!(is_der_signature(&[0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x01]));
+
+        assert!(ecdsa_raw_to_der(&[]).is_err());
+        assert!(ecdsa_raw_to_der(&[0u8; 63]).is_err());
+        assert!(ecdsa_raw_to_der(&[0u8; 70]).is_err());
+    }
 }
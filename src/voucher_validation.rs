@@ -0,0 +1,433 @@
+/*
+ * Copyright [2021] <mcr@sandelman.ca>
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+ *
+ */
+
+/*
+ * RFC8995 section 5.6.1: what the pledge has to check before it believes
+ * a voucher.  Each check that can fail has its own verdict, so that the
+ * reason can be logged, and reported back to the registrar.
+ */
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use mbedtls::alloc::List as MbedtlsList;
+use mbedtls::x509::Certificate;
+use mbedtls::hash as mbedtls_hash;
+
+use crate::asn1;
+use crate::utils;
+use crate::custom_voucher::{CustomVoucher, Validate};
+use minerva_voucher::attr::*;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VoucherVerdict {
+    Accepted,
+    Malformed,
+    BadSignature,
+    SerialNumberMissing,
+    SerialNumberMismatch,
+    NonceMissing,
+    NonceMismatch,
+    AssertionMissing,
+    ExpiresOnMissing,
+    Expired,
+    PinnedDomainMissing,
+    RegistrarNotPinned,
+}
+
+impl VoucherVerdict {
+    pub fn is_accepted(&self) -> bool {
+        *self == VoucherVerdict::Accepted
+    }
+
+    /// a fixed token for the verdict, for the registrar to sort failures by
+    pub fn reason(&self) -> &'static str {
+        match self {
+            VoucherVerdict::Accepted             => "accepted",
+            VoucherVerdict::Malformed            => "malformed",
+            VoucherVerdict::BadSignature         => "bad-signature",
+            VoucherVerdict::SerialNumberMissing  => "serial-number-missing",
+            VoucherVerdict::SerialNumberMismatch => "serial-number-mismatch",
+            VoucherVerdict::NonceMissing         => "nonce-missing",
+            VoucherVerdict::NonceMismatch        => "nonce-mismatch",
+            VoucherVerdict::AssertionMissing     => "assertion-missing",
+            VoucherVerdict::ExpiresOnMissing     => "expires-on-missing",
+            VoucherVerdict::Expired              => "expired",
+            VoucherVerdict::PinnedDomainMissing  => "pinned-domain-missing",
+            VoucherVerdict::RegistrarNotPinned   => "registrar-not-pinned",
+        }
+    }
+}
+
+impl fmt::Display for VoucherVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoucherVerdict::Accepted => {
+                write!(f, "voucher accepted")
+            },
+            VoucherVerdict::Malformed => {
+                write!(f, "voucher could not be parsed")
+            },
+            VoucherVerdict::BadSignature => {
+                write!(f, "voucher signature does not validate against MASA trust anchor")
+            },
+            VoucherVerdict::SerialNumberMissing => {
+                write!(f, "voucher has no serial-number")
+            },
+            VoucherVerdict::SerialNumberMismatch => {
+                write!(f, "voucher serial-number is not that of this device")
+            },
+            VoucherVerdict::NonceMissing => {
+                write!(f, "voucher has no nonce")
+            },
+            VoucherVerdict::NonceMismatch => {
+                write!(f, "voucher nonce does not match voucher-request")
+            },
+            VoucherVerdict::AssertionMissing => {
+                write!(f, "voucher has no assertion")
+            },
+            VoucherVerdict::ExpiresOnMissing => {
+                write!(f, "nonceless voucher has no expires-on")
+            },
+            VoucherVerdict::Expired => {
+                write!(f, "voucher has expired")
+            },
+            VoucherVerdict::PinnedDomainMissing => {
+                write!(f, "voucher does not pin a domain trust anchor")
+            },
+            VoucherVerdict::RegistrarNotPinned => {
+                write!(f, "registrar certificate does not chain to pinned-domain-cert")
+            }
+        }
+    }
+}
+
+/// what the voucher is checked against
+pub struct Expectations<'a> {
+    pub masa_anchor:     &'a [u8],
+    pub serial_number:   &'a [u8],
+    pub nonce:           Option<&'a [u8]>,
+    pub allow_nonceless: bool,
+    pub now:             u64,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn validate_voucher(voucher: &CustomVoucher, expect: &Expectations) -> VoucherVerdict {
+    if voucher.validate(Some(expect.masa_anchor)).is_err() {
+        return VoucherVerdict::BadSignature;
+    }
+    check_contents(voucher, expect)
+}
+
+/// all the checks that do not involve the signature
+pub fn check_contents(voucher: &CustomVoucher, expect: &Expectations) -> VoucherVerdict {
+    match voucher.get(ATTR_SERIAL_NUMBER) {
+        Some(Attr::SerialNumber(sn)) if sn.as_slice() == expect.serial_number => {},
+        Some(_) => return VoucherVerdict::SerialNumberMismatch,
+        None    => return VoucherVerdict::SerialNumberMissing,
+    }
+
+    let verdict = check_nonce(expect.nonce, voucher, expect.allow_nonceless);
+    if !verdict.is_accepted() {
+        return verdict;
+    }
+
+    if voucher.get(ATTR_ASSERTION).is_none() {
+        return VoucherVerdict::AssertionMissing;
+    }
+
+    match voucher.get(ATTR_EXPIRES_ON) {
+        Some(Attr::ExpiresOn(expires_on)) if *expires_on <= expect.now => VoucherVerdict::Expired,
+        Some(_) => VoucherVerdict::Accepted,
+        // a nonceless voucher would otherwise be good forever
+        None if voucher.get(ATTR_NONCE).is_none() => VoucherVerdict::ExpiresOnMissing,
+        None => VoucherVerdict::Accepted,
+    }
+}
+
+/*
+ * a nonceless voucher is only acceptable when this device was deliberately
+ * set up to accept them (RFC8366 section 5), otherwise it could be a replay.
+ */
+pub fn check_nonce(expected: Option<&[u8]>, voucher: &CustomVoucher, allow_nonceless: bool) -> VoucherVerdict {
+    match (voucher.get(ATTR_NONCE), expected) {
+        (Some(Attr::Nonce(nonce)), Some(expected)) if nonce.as_slice() == expected => VoucherVerdict::Accepted,
+        (Some(_), _) => VoucherVerdict::NonceMismatch,
+        (None, _) if allow_nonceless => VoucherVerdict::Accepted,
+        (None, _) => VoucherVerdict::NonceMissing,
+    }
+}
+
+/// the domain trust anchor that an accepted voucher pins.
+/// Constrained vouchers (RFC9148) may pin the registrar's SubjectPublicKeyInfo,
+/// or the SHA-256 of it, instead of a certificate.
+#[derive(Clone, PartialEq, Debug)]
+pub enum PinnedDomain {
+    Cert(Vec<u8>),
+    Pubk(Vec<u8>),
+    PubkSha256(Vec<u8>),
+}
+
+// PEM labels of the saved anchor, one for each form
+const PINNED_CERT_LABEL:   &str = "CERTIFICATE";
+const PINNED_PUBK_LABEL:   &str = "PUBLIC KEY";
+const PINNED_SHA256_LABEL: &str = "PUBLIC KEY SHA256";
+
+impl PinnedDomain {
+    /// as PEM, for keeping the anchor after the voucher is gone
+    pub fn to_pem(&self) -> String {
+        match self {
+            PinnedDomain::Cert(der)        => utils::der_to_pem(PINNED_CERT_LABEL, der),
+            PinnedDomain::Pubk(der)        => utils::der_to_pem(PINNED_PUBK_LABEL, der),
+            PinnedDomain::PubkSha256(hash) => utils::der_to_pem(PINNED_SHA256_LABEL, hash),
+        }
+    }
+
+    pub fn from_pem(pem: &[u8]) -> Option<PinnedDomain> {
+        let text = String::from_utf8_lossy(pem);
+        let label = text.lines()
+            .filter_map(|line| line.trim().strip_prefix("-----BEGIN ")?.strip_suffix("-----"))
+            .next()?;
+        let der = utils::pem_to_der(pem)?;
+        match label {
+            PINNED_CERT_LABEL   => Some(PinnedDomain::Cert(der)),
+            PINNED_PUBK_LABEL   => Some(PinnedDomain::Pubk(der)),
+            PINNED_SHA256_LABEL => Some(PinnedDomain::PubkSha256(der)),
+            _ => None,
+        }
+    }
+}
+
+pub fn pinned_domain(voucher: &CustomVoucher) -> Option<PinnedDomain> {
+    if let Some(Attr::PinnedDomainCert(cert)) = voucher.get(ATTR_PINNED_DOMAIN_CERT) {
+        return Some(PinnedDomain::Cert(cert.clone()));
+    }
+    if let Some(Attr::PinnedDomainPubk(pubk)) = voucher.get(ATTR_PINNED_DOMAIN_PUBK) {
+        return Some(PinnedDomain::Pubk(pubk.clone()));
+    }
+    if let Some(Attr::PinnedDomainPubkSha256(hash)) = voucher.get(ATTR_PINNED_DOMAIN_PUBK_SHA256) {
+        return Some(PinnedDomain::PubkSha256(hash.clone()));
+    }
+    None
+}
+
+/*
+ * RFC8995 section 5.6.2: the registrar certificate that was provisionally
+ * accepted during the TLS handshake must chain to the pinned-domain-cert.
+ * `peer_chain` is what the registrar presented, end-entity first.
+ */
+pub fn verify_registrar(pinned: &PinnedDomain, peer_chain: &[Vec<u8>]) -> VoucherVerdict {
+    let registrar = match peer_chain.first() {
+        Some(cert) => cert,
+        None => return VoucherVerdict::RegistrarNotPinned,
+    };
+
+    match pinned {
+        PinnedDomain::Cert(anchor) => {
+            // the voucher may pin the registrar certificate itself
+            if registrar == anchor {
+                return VoucherVerdict::Accepted;
+            }
+            match chain_verifies(peer_chain, anchor) {
+                Ok(true) => VoucherVerdict::Accepted,
+                _ => VoucherVerdict::RegistrarNotPinned,
+            }
+        },
+        PinnedDomain::Pubk(pubk) => {
+            match asn1::parse_certificate(registrar) {
+                Ok(parts) if parts.spki.raw == pubk.as_slice() => VoucherVerdict::Accepted,
+                _ => VoucherVerdict::RegistrarNotPinned,
+            }
+        },
+        PinnedDomain::PubkSha256(hash) => {
+            match spki_sha256(registrar) {
+                Some(digest) if digest == *hash => VoucherVerdict::Accepted,
+                _ => VoucherVerdict::RegistrarNotPinned,
+            }
+        }
+    }
+}
+
+fn spki_sha256(cert: &[u8]) -> Option<Vec<u8>> {
+    let parts = asn1::parse_certificate(cert).ok()?;
+    let mut digest = vec![0u8; 32];
+    mbedtls_hash::Md::hash(mbedtls_hash::Type::Sha256, parts.spki.raw, &mut digest).ok()?;
+    Some(digest)
+}
+
+/// `peer_chain` is end-entity first, the anchor is DER
+pub fn chain_verifies(peer_chain: &[Vec<u8>], anchor: &[u8]) -> Result<bool, mbedtls::Error> {
+    let mut chain = MbedtlsList::<Certificate>::new();
+    for der in peer_chain {
+        chain.push(Certificate::from_der(der)?);
+    }
+
+    let mut trust = MbedtlsList::<Certificate>::new();
+    trust.push(Certificate::from_der(anchor)?);
+
+    Ok(Certificate::verify(&chain, &trust, None, None).is_ok())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::custom_voucher::VoucherAlgorithm;
+    use crate::test_fixtures::{MASA_CERT_PEM, MASA_KEY_PEM};
+    use crate::test_fixtures::{MINIMAL_CERT as REGISTRAR, MINIMAL_SPKI as REGISTRAR_SPKI};
+
+    static SERIAL: &[u8] = b"00-D0-E5-F2-00-02";
+    static NONCE:  &[u8] = &[1, 2, 3, 4];
+
+    fn expectations(allow_nonceless: bool) -> Expectations<'static> {
+        Expectations {
+            masa_anchor: &[],
+            serial_number: SERIAL,
+            nonce: Some(NONCE),
+            allow_nonceless: allow_nonceless,
+            now: 1635000000,
+        }
+    }
+
+    fn voucher() -> CustomVoucher {
+        let mut vch = CustomVoucher::new_vrq();
+        vch.set(Attr::Assertion(Assertion::Logged))
+            .set(Attr::SerialNumber(SERIAL.to_vec()))
+            .set(Attr::Nonce(NONCE.to_vec()));
+        vch
+    }
+
+    #[test]
+    fn voucher_accepted() {
+        assert_eq!(check_contents(&voucher(), &expectations(false)), VoucherVerdict::Accepted);
+    }
+
+    /// voucher(), signed by the MASA and as it arrives
+    fn signed_voucher() -> Vec<u8> {
+        let mut vch = voucher();
+        vch.sign_as(MASA_KEY_PEM, VoucherAlgorithm::ES256).unwrap();
+        vch.serialize().unwrap()
+    }
+
+    #[test]
+    fn voucher_signed_by_masa() {
+        let vch = CustomVoucher::try_from(signed_voucher().as_slice()).unwrap();
+        let expect = Expectations { masa_anchor: MASA_CERT_PEM, ..expectations(false) };
+        assert_eq!(validate_voucher(&vch, &expect), VoucherVerdict::Accepted);
+
+        // the MASA key is not a trust anchor
+        let expect = Expectations { masa_anchor: MASA_KEY_PEM, ..expectations(false) };
+        assert_eq!(validate_voucher(&vch, &expect), VoucherVerdict::BadSignature);
+    }
+
+    #[test]
+    fn voucher_tampered() {
+        let mut raw = signed_voucher();
+        *raw.last_mut().unwrap() ^= 0x01;
+        let vch = CustomVoucher::try_from(raw.as_slice()).unwrap();
+        let expect = Expectations { masa_anchor: MASA_CERT_PEM, ..expectations(false) };
+        assert_eq!(validate_voucher(&vch, &expect), VoucherVerdict::BadSignature);
+    }
+
+    #[test]
+    fn voucher_wrong_serial_number() {
+        let mut vch = voucher();
+        vch.set(Attr::SerialNumber(b"00-D0-E5-F2-00-03".to_vec()));
+        assert_eq!(check_contents(&vch, &expectations(false)), VoucherVerdict::SerialNumberMismatch);
+    }
+
+    #[test]
+    fn voucher_nonce_matches() {
+        let vch = voucher();
+        assert_eq!(check_nonce(Some(&[1, 2, 3, 5]), &vch, false), VoucherVerdict::NonceMismatch);
+        assert_eq!(check_nonce(None, &vch, true), VoucherVerdict::NonceMismatch);
+    }
+
+    #[test]
+    fn voucher_nonceless() {
+        let mut vch = CustomVoucher::new_vrq();
+        vch.set(Attr::Assertion(Assertion::Logged))
+            .set(Attr::SerialNumber(SERIAL.to_vec()));
+
+        assert_eq!(check_contents(&vch, &expectations(false)), VoucherVerdict::NonceMissing);
+        assert_eq!(check_contents(&vch, &expectations(true)), VoucherVerdict::ExpiresOnMissing);
+
+        vch.set(Attr::ExpiresOn(1635000001));
+        assert_eq!(check_contents(&vch, &expectations(true)), VoucherVerdict::Accepted);
+    }
+
+    #[test]
+    fn registrar_pinned_directly() {
+        let registrar = vec![0x30, 0x03, 0x02, 0x01, 0x01];
+        let pinned = PinnedDomain::Cert(registrar.clone());
+
+        assert_eq!(verify_registrar(&pinned, &[registrar]), VoucherVerdict::Accepted);
+        assert_eq!(verify_registrar(&pinned, &[]), VoucherVerdict::RegistrarNotPinned);
+    }
+
+    #[test]
+    fn registrar_pinned_pubk() {
+        let chain = [REGISTRAR.to_vec()];
+
+        let pinned = PinnedDomain::Pubk(REGISTRAR_SPKI.to_vec());
+        assert_eq!(verify_registrar(&pinned, &chain), VoucherVerdict::Accepted);
+
+        let pinned = PinnedDomain::Pubk(vec![0x30, 0x03, 0x03, 0x01, 0x01]);
+        assert_eq!(verify_registrar(&pinned, &chain), VoucherVerdict::RegistrarNotPinned);
+    }
+
+    #[test]
+    fn registrar_pinned_pubk_sha256() {
+        let chain = [REGISTRAR.to_vec()];
+
+        let mut digest = vec![0u8; 32];
+        mbedtls_hash::Md::hash(mbedtls_hash::Type::Sha256, REGISTRAR_SPKI, &mut digest).unwrap();
+        let pinned = PinnedDomain::PubkSha256(digest.clone());
+        assert_eq!(verify_registrar(&pinned, &chain), VoucherVerdict::Accepted);
+
+        digest[0] ^= 0xff;
+        let pinned = PinnedDomain::PubkSha256(digest);
+        assert_eq!(verify_registrar(&pinned, &chain), VoucherVerdict::RegistrarNotPinned);
+    }
+
+    #[test]
+    fn pinned_domain_saved() {
+        for pinned in vec![PinnedDomain::Cert(REGISTRAR.to_vec()),
+                           PinnedDomain::Pubk(REGISTRAR_SPKI.to_vec()),
+                           PinnedDomain::PubkSha256(vec![0x5a; 32])] {
+            assert_eq!(PinnedDomain::from_pem(pinned.to_pem().as_bytes()), Some(pinned));
+        }
+        let key = utils::der_to_pem("PRIVATE KEY", REGISTRAR_SPKI);
+        assert_eq!(PinnedDomain::from_pem(key.as_bytes()), None);
+    }
+
+    #[test]
+    fn voucher_expired() {
+        let mut vch = voucher();
+        vch.set(Attr::ExpiresOn(1634999999));
+        assert_eq!(check_contents(&vch, &expectations(false)), VoucherVerdict::Expired);
+    }
+}
+
+/*
+ * Local Variables:
+ * mode: rust
+ * compile-command: "cd .. && cargo build"
+ * End:
+ */